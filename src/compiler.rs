@@ -1,174 +1,718 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::mem;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::slice;
 use std::str::FromStr;
 
 use crate::error::CompileError;
 use crate::error::CompileErrorKind;
-use crate::op::Add;
-use crate::op::Cmp;
-use crate::op::End;
-use crate::op::Jel;
-use crate::op::Jif;
-use crate::op::Jmp;
-use crate::op::Mov;
+use crate::error::CompileErrors;
+use crate::error::CompileWarning;
+use crate::error::CompileWarningKind;
+use crate::error::Note;
+use crate::lexer::Lexer;
 use crate::op::OpWrap;
-use crate::op::Out;
-use crate::op::Pin;
-use crate::op::Ret;
-use crate::op::Sub;
-use crate::op::Utf;
+use crate::parse_value;
 use crate::unwrap_or_throw;
 use crate::Executable;
-use crate::Op;
+use crate::Instruction;
+use crate::Int;
+use crate::Label;
+use crate::Location;
+use crate::Pos;
 use crate::Ref;
+use crate::Source;
+use crate::Span;
 use crate::Token;
 use crate::TokenKind;
+use crate::Value;
 
 #[derive(Default)]
 pub struct Compiler {
     raw: String,
+    path: Option<PathBuf>,
+    prefix: String,
+    source: usize,
+    includes: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    constants: HashMap<String, Value>,
+    expansions: Vec<Expansion>,
+    blocks: usize,
+    records: HashMap<String, Vec<String>>,
+    record_vars: HashMap<String, String>,
+    record_arrays: HashMap<String, String>,
+    errors: Vec<CompileError>,
+}
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Vec<Token>>,
+}
+
+struct Expansion {
+    name: String,
+    call: Token,
+}
+
+struct Block {
+    keyword: String,
+    id: usize,
+    opener: Token,
+    has_else: bool,
+}
+
+struct Include {
+    file: String,
+    tokens: Vec<Token>,
+}
+
+struct Parsed {
+    instruction: Instruction,
+    tokens: Vec<Token>,
+    source: usize,
+    notes: Vec<Note>,
 }
 
 impl Compiler {
-    pub fn compile(&mut self, raw: &str) -> Result<Executable, CompileError> {
+    pub fn with_path(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Self {
+            includes: vec![path.canonicalize().unwrap_or_else(|_| path.clone())],
+            path: Some(path),
+            ..Default::default()
+        }
+    }
+    pub fn compile(&mut self, raw: &str) -> Result<Executable, CompileErrors> {
+        *self = Compiler {
+            path: self.path.take(),
+            prefix: mem::take(&mut self.prefix),
+            includes: mem::take(&mut self.includes),
+            ..Default::default()
+        };
+        let mut exe = Executable {
+            ops: Vec::new(),
+            sources: Vec::new(),
+            warnings: Vec::new(),
+        };
+        let mut parsed = Vec::new();
+        let mut errors = Vec::new();
+        self.compile_into(raw, &mut exe, &mut parsed, &mut errors);
+        let pins = resolve_pins(&exe.sources, &parsed, &mut errors);
+        check_calls(&exe.sources, &parsed, &pins, &mut errors);
+        if errors.is_empty() {
+            exe.warnings
+                .extend(check_returns(&exe.sources, &parsed, &pins));
+            exe.warnings
+                .extend(check_definitions(&exe.sources, &parsed, &pins, &mut errors));
+        }
+        if !errors.is_empty() {
+            errors.sort_by_key(|(source, err)| (*source, err.span.start.offset));
+            return Err(CompileErrors(
+                errors.into_iter().map(|(_, err)| err).collect(),
+            ));
+        }
+        exe.ops = parsed
+            .into_iter()
+            .map(|parsed| OpWrap {
+                op: parsed.instruction.to_op(&pins),
+                instruction: parsed.instruction,
+                tokens: parsed.tokens,
+                source: parsed.source,
+            })
+            .collect();
+        Ok(exe)
+    }
+    fn compile_into(
+        &mut self,
+        raw: &str,
+        exe: &mut Executable,
+        parsed: &mut Vec<Parsed>,
+        errors: &mut Vec<(usize, CompileError)>,
+    ) {
         self.raw = raw.to_string();
-        let tokens = self.tokenize()?;
-        Ok(Executable {
-            ops: self.parse(&tokens)?,
+        self.source = exe.sources.len();
+        exe.sources.push(Source {
+            path: self.path.clone(),
             raw: raw.to_string(),
-        })
+        });
+        let tokens = self.tokenize();
+        let tokens = self.expand(tokens);
+        let tokens = self.define_constants(tokens);
+        let tokens = self.lower_blocks(tokens);
+        let tokens = self.lower_records(tokens);
+        let (ops, includes) = self.parse(tokens);
+        errors.extend(self.errors.drain(..).map(|err| (self.source, err)));
+        parsed.extend(ops);
+        if includes.is_empty() {
+            return;
+        }
+        parsed.push(Parsed {
+            instruction: Instruction::End,
+            tokens: vec![Token {
+                kind: TokenKind::Symbol("end".to_string()),
+                span: Span::default(),
+                expansion: None,
+            }],
+            source: self.source,
+            notes: Vec::new(),
+        });
+        let mut included = Vec::new();
+        let mut modules = HashMap::new();
+        for include in includes {
+            let dir = self.path.as_ref().and_then(|path| path.parent());
+            let path = dir.unwrap_or(Path::new("")).join(&include.file);
+            let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if self.includes.contains(&canonical) {
+                let err = self.throw_at(
+                    CompileErrorKind::CyclicInclude(include.file),
+                    &include.tokens,
+                    1,
+                );
+                errors.push((self.source, *err));
+                continue;
+            }
+            if included.contains(&canonical) {
+                continue;
+            }
+            let module = module_name(&include.file);
+            if modules
+                .get(&module)
+                .is_some_and(|other| *other != canonical)
+            {
+                let err = self.throw_at(
+                    CompileErrorKind::ModuleCollision(module),
+                    &include.tokens,
+                    1,
+                );
+                errors.push((self.source, *err));
+                continue;
+            }
+            modules.insert(module.clone(), canonical.clone());
+            let raw = match fs::read_to_string(&path) {
+                Ok(raw) => raw,
+                Err(err) => {
+                    let err = self.throw_at(
+                        CompileErrorKind::MissingFile(include.file, err.to_string()),
+                        &include.tokens,
+                        1,
+                    );
+                    errors.push((self.source, *err));
+                    continue;
+                }
+            };
+            let mut includes = self.includes.clone();
+            includes.push(canonical.clone());
+            included.push(canonical);
+            let mut compiler = Compiler {
+                prefix: self.qualify(&module),
+                path: Some(path),
+                includes,
+                ..Default::default()
+            };
+            compiler.compile_into(&raw, exe, parsed, errors);
+        }
+    }
+    fn tokenize(&mut self) -> Vec<Token> {
+        let (tokens, errors) = Lexer::new(&self.raw).tokenize();
+        for (kind, span) in errors {
+            self.errors.push(*self.throw_span(kind, span, None));
+        }
+        tokens
     }
-    fn tokenize(&self) -> Result<Vec<Token>, CompileError> {
+    fn expand(&mut self, tokens: Vec<Token>) -> Vec<Token> {
+        let mut rest = Vec::new();
+        let mut lines = split_lines(tokens).into_iter();
+        while let Some(line) = lines.next() {
+            if op_symbol(&line) != Some("def") {
+                rest.push(line);
+                continue;
+            }
+            let header = self.macro_header(&line);
+            let mut body = Vec::new();
+            let mut depth = 0;
+            let mut terminated = false;
+            for body_line in lines.by_ref() {
+                match op_symbol(&body_line) {
+                    Some("enddef") if depth == 0 => {
+                        terminated = true;
+                        break;
+                    }
+                    Some("enddef") => depth -= 1,
+                    Some("def") => {
+                        self.errors.push(*self.throw_at(
+                            CompileErrorKind::InvalidLocation,
+                            &body_line,
+                            0,
+                        ));
+                        depth += 1;
+                    }
+                    _ if depth == 0 => body.push(body_line),
+                    _ => (),
+                }
+            }
+            match header {
+                Ok((name, _)) if !terminated => {
+                    self.errors.push(*self.throw_at(
+                        CompileErrorKind::UnterminatedMacro(name),
+                        &line,
+                        1,
+                    ));
+                }
+                Ok((name, params)) => {
+                    self.macros.insert(name, Macro { params, body });
+                }
+                Err(err) => self.errors.push(*err),
+            }
+        }
         let mut tokens = Vec::new();
-        let mut symbol = Vec::new();
-        let mut value = Vec::new();
-        let mut cast = false;
-        let mut block = false;
-        let mut y = 0;
-        let mut x = 0;
-        for c in self.raw.chars() {
-            if cast && block && c != '\'' && c != '\r' && c != '\n' {
-                symbol.push(c);
+        self.expand_lines(rest, &mut Vec::new(), &mut tokens);
+        tokens
+    }
+    fn macro_header(&self, line: &[Token]) -> CompileResult<(String, Vec<String>)> {
+        let mut args = line_args(line).into_iter();
+        let name = match args.next().map(|token| &token.kind) {
+            Some(TokenKind::Symbol(name)) => name.clone(),
+            _ => return Err(self.throw_at(CompileErrorKind::ExpectedArgument, line, 0)),
+        };
+        let mut params = Vec::new();
+        for (i, token) in args.enumerate() {
+            match &token.kind {
+                TokenKind::Symbol(param) => params.push(param.clone()),
+                _ => return Err(self.throw_at(CompileErrorKind::ExpectedPosition, line, i + 2)),
+            }
+        }
+        Ok((name, params))
+    }
+    fn expand_lines(
+        &mut self,
+        lines: Vec<Vec<Token>>,
+        stack: &mut Vec<String>,
+        tokens: &mut Vec<Token>,
+    ) {
+        for line in lines {
+            let name = match op_symbol(&line) {
+                Some(name) if self.macros.contains_key(name) => name.to_string(),
+                _ => {
+                    tokens.extend(line);
+                    continue;
+                }
+            };
+            if stack.contains(&name) {
+                self.errors
+                    .push(*self.throw_at(CompileErrorKind::RecursiveMacro(name), &line, 0));
+                continue;
+            }
+            let Macro { params, body } = self.macros[&name].clone();
+            let args = line_args(&line);
+            if args.len() != params.len() {
+                self.errors.push(*self.throw_at(
+                    CompileErrorKind::WrongArgumentCount(params.len(), args.len()),
+                    &line,
+                    0,
+                ));
+                continue;
+            }
+            let expansion = self.expansions.len();
+            self.expansions.push(Expansion {
+                name: name.clone(),
+                call: line[0].clone(),
+            });
+            let pins = body
+                .iter()
+                .filter(|body_line| matches!(body_line[0].kind, TokenKind::Pin))
+                .filter_map(|body_line| match &body_line.get(1)?.kind {
+                    TokenKind::Symbol(label) => Some(label.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            let body = body
+                .into_iter()
+                .map(|mut body_line| {
+                    let is_label = matches!(body_line[0].kind, TokenKind::Pin)
+                        || op_symbol(&body_line).is_some_and(|op| JUMP_OPS.contains(&op));
+                    for (i, token) in body_line.iter_mut().enumerate() {
+                        token.expansion = Some(expansion);
+                        let symbol = match &token.kind {
+                            TokenKind::Symbol(symbol) => symbol.clone(),
+                            _ => continue,
+                        };
+                        token.kind = match params.iter().position(|param| *param == symbol) {
+                            Some(param) => args[param].kind.clone(),
+                            None => TokenKind::Symbol(substitute(&symbol, &params, &args)),
+                        };
+                        if let TokenKind::Symbol(label) = &token.kind {
+                            if i == 1 && is_label && pins.contains(label) {
+                                token.kind = TokenKind::Symbol(format!("{}#{}", label, expansion));
+                            }
+                        }
+                    }
+                    body_line
+                })
+                .collect();
+            stack.push(name);
+            self.expand_lines(body, stack, tokens);
+            stack.pop();
+        }
+    }
+    fn define_constants(&mut self, tokens: Vec<Token>) -> Vec<Token> {
+        let mut rest = Vec::new();
+        for line in split_lines(tokens) {
+            if op_symbol(&line) != Some("const") {
+                rest.extend(line);
                 continue;
             }
-            match c {
-                c if c.is_alphabetic() || c == '_' => {
-                    symbol.push(c);
+            if let Err(err) = self.define_constant(&line) {
+                self.errors.push(*err);
+            }
+        }
+        for token in &rest {
+            let TokenKind::Symbol(symbol) = &token.kind else {
+                continue;
+            };
+            let mut indices = symbol
+                .split('[')
+                .skip(1)
+                .filter_map(|s| s.split(']').next());
+            if indices.any(|index| matches!(self.constants.get(index), Some(Value::Float(_)))) {
+                let err = self.throw_at(CompileErrorKind::InvalidIndex, slice::from_ref(token), 0);
+                self.errors.push(*err);
+            }
+        }
+        rest
+    }
+    fn define_constant(&mut self, line: &[Token]) -> CompileResult<()> {
+        let args = line_args(line);
+        if args.len() != 2 {
+            return Err(self.throw_at(
+                CompileErrorKind::WrongArgumentCount(2, args.len()),
+                line,
+                0,
+            ));
+        }
+        let name = match &args[0].kind {
+            TokenKind::Symbol(name) if !name.contains(['[', '.']) => name.clone(),
+            _ => return Err(self.throw_at(CompileErrorKind::ExpectedPosition, line, 1)),
+        };
+        if self.constants.contains_key(&name) {
+            return Err(self.throw_at(CompileErrorKind::DuplicateConstant(name), line, 1));
+        }
+        let value = match &args[1].kind {
+            TokenKind::Value(value) => parse_value(value).ok(),
+            TokenKind::Symbol(symbol) => self.constants.get(symbol).copied(),
+            TokenKind::Cast(cast) => match cast_value(cast) {
+                Some(value) => Some(value),
+                None => return Err(self.throw_at(CompileErrorKind::InvalidCast, line, 2)),
+            },
+            _ => None,
+        };
+        let value = unwrap_or_throw!(
+            value,
+            self.throw_at(CompileErrorKind::ExpectedValue, line, 2)
+        );
+        self.constants.insert(name, value);
+        Ok(())
+    }
+    fn lower_blocks(&mut self, tokens: Vec<Token>) -> Vec<Token> {
+        let mut lowered = Vec::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        for line in split_lines(tokens) {
+            let keyword = match op_symbol(&line) {
+                Some(keyword @ ("if" | "else" | "fi" | "while" | "done")) => keyword.to_string(),
+                _ => {
+                    lowered.extend(line);
+                    continue;
                 }
-                c if c.is_alphanumeric() => {
-                    value.push(c);
+            };
+            let at = &line[0];
+            if keyword == "if" || keyword == "while" {
+                let id = self.blocks;
+                self.blocks += 1;
+                let target = if keyword == "if" { "else" } else { "done" };
+                if keyword == "while" {
+                    lowered.extend(lower_pin(at, block_label("while", id)));
                 }
-                '&' => {
-                    cast = true;
+                match self.condition(&line) {
+                    Ok((left, jump, right)) => {
+                        lowered.extend(lower_op(at, "cmp", vec![left, right]));
+                        lowered.extend(lower_op(at, jump, vec![label_token(at, target, id)]));
+                    }
+                    Err(err) => self.errors.push(*err),
                 }
-                '\'' => {
-                    if !block && !cast {
-                        return Err(self.throw_at(
-                            CompileErrorKind::InvalidCast,
-                            &tokens,
-                            tokens.len() - 1,
+                blocks.push(Block {
+                    keyword,
+                    id,
+                    opener: at.clone(),
+                    has_else: false,
+                });
+                continue;
+            }
+            let args = line_args(&line).len();
+            if args != 0 {
+                self.errors.push(*self.throw_at(
+                    CompileErrorKind::WrongArgumentCount(0, args),
+                    &line,
+                    0,
+                ));
+            }
+            let expected = if keyword == "done" { "while" } else { "if" };
+            let mut block = match blocks.pop() {
+                Some(block)
+                    if block.keyword == expected && !(keyword == "else" && block.has_else) =>
+                {
+                    block
+                }
+                open => {
+                    let mut err =
+                        self.throw_at(CompileErrorKind::UnmatchedBlock(keyword), &line, 0);
+                    if let Some(block) = open {
+                        err.notes.push(self.note_at(
+                            format!("Innermost open block '{}' starts here", block.keyword),
+                            &block.opener,
                         ));
+                        blocks.push(block);
                     }
-                    block = !block;
+                    self.errors.push(*err);
+                    continue;
+                }
+            };
+            if keyword == "else" {
+                lowered.extend(lower_op(at, "jmp", vec![label_token(at, "fi", block.id)]));
+                lowered.extend(lower_pin(at, block_label("else", block.id)));
+                block.has_else = true;
+                blocks.push(block);
+                continue;
+            }
+            lowered.extend(close_block(at, &block));
+        }
+        for block in blocks {
+            lowered.extend(close_block(&block.opener, &block));
+            self.errors.push(*self.throw_at(
+                CompileErrorKind::UnclosedBlock(block.keyword),
+                &[block.opener],
+                0,
+            ));
+        }
+        lowered
+    }
+    fn condition(&self, line: &[Token]) -> CompileResult<(Token, &'static str, Token)> {
+        let args = line_args(line);
+        if args.len() != 3 {
+            return Err(self.throw_at(CompileErrorKind::InvalidCondition, line, 0));
+        }
+        let jump = match &args[1].kind {
+            TokenKind::Symbol(operator) => match operator.as_str() {
+                "==" => "jel",
+                "!=" => "jif",
+                "<" => "jge",
+                ">" => "jle",
+                "<=" => "jgt",
+                ">=" => "jlt",
+                _ => return Err(self.throw_at(CompileErrorKind::InvalidCondition, line, 2)),
+            },
+            _ => return Err(self.throw_at(CompileErrorKind::InvalidCondition, line, 2)),
+        };
+        Ok((args[0].clone(), jump, args[2].clone()))
+    }
+    fn lower_records(&mut self, tokens: Vec<Token>) -> Vec<Token> {
+        let mut lowered = Vec::new();
+        for line in split_lines(tokens) {
+            if let Err(err) = self.lower_record_line(line, &mut lowered) {
+                self.errors.push(*err);
+            }
+        }
+        lowered
+    }
+    fn lower_record_line(
+        &mut self,
+        line: Vec<Token>,
+        lowered: &mut Vec<Token>,
+    ) -> CompileResult<()> {
+        let args = line_args(&line);
+        match op_symbol(&line) {
+            Some("rec") => {
+                let name = match args.first().map(|token| &token.kind) {
+                    Some(TokenKind::Symbol(name)) => name.clone(),
+                    _ => return Err(self.throw_at(CompileErrorKind::ExpectedArgument, &line, 0)),
+                };
+                if self.records.contains_key(&name) {
+                    return Err(self.throw_at(CompileErrorKind::DuplicateRecord(name), &line, 1));
                 }
-                ' ' | '\t' | '\r' | '\n' => {
-                    if cast {
-                        if block {
+                let mut fields: Vec<String> = Vec::new();
+                for (i, token) in args.iter().enumerate().skip(1) {
+                    let field = match &token.kind {
+                        TokenKind::Symbol(field) if !field.contains(['[', '.', '*']) => field,
+                        _ => {
                             return Err(self.throw_at(
-                                CompileErrorKind::InvalidBlock,
-                                &tokens,
-                                tokens.len() - 1,
-                            ));
+                                CompileErrorKind::ExpectedPosition,
+                                &line,
+                                i + 1,
+                            ))
                         }
-                        tokens.push(Token {
-                            kind: TokenKind::Cast(String::from_iter(symbol.clone())),
-                            pos: (x, y),
-                        });
-                        symbol.clear();
-                        cast = false;
-                    }
-                    if !symbol.is_empty() {
-                        tokens.push(Token {
-                            kind: TokenKind::Symbol(String::from_iter(symbol.clone())),
-                            pos: (y, x),
-                        });
-                        symbol.clear();
-                    }
-                    if !value.is_empty() {
-                        tokens.push(Token {
-                            kind: TokenKind::Value(String::from_iter(value.clone())),
-                            pos: (y, x),
-                        });
-                        value.clear();
+                    };
+                    if fields.contains(field) {
+                        return Err(self.throw_at(
+                            CompileErrorKind::DuplicateField(field.clone()),
+                            &line,
+                            i + 1,
+                        ));
                     }
-                    if c == '\n' {
-                        tokens.push(Token {
-                            kind: TokenKind::Break,
-                            pos: (y, x),
-                        });
-                        y += 1;
-                        x = 0;
+                    fields.push(field.clone());
+                }
+                if fields.is_empty() {
+                    return Err(self.throw_at(CompileErrorKind::ExpectedArgument, &line, 0));
+                }
+                self.records.insert(name, fields);
+            }
+            Some(op @ ("var" | "loc" | "arr")) if args.len() == 2 + (op == "arr") as usize => {
+                let name = match &args[0].kind {
+                    TokenKind::Symbol(name) if !name.contains(['[', '.', '*']) => name.clone(),
+                    _ => return Err(self.throw_at(CompileErrorKind::ExpectedPosition, &line, 1)),
+                };
+                let record = match &args[args.len() - 1].kind {
+                    TokenKind::Symbol(record) => record.clone(),
+                    _ => return Err(self.throw_at(CompileErrorKind::ExpectedArgument, &line, 0)),
+                };
+                let fields = unwrap_or_throw!(
+                    self.records.get(&record),
+                    self.throw_at(CompileErrorKind::UnknownRecord(record), &line, args.len())
+                );
+                if op != "var" {
+                    for field in fields {
+                        let mut field_line = line[..line.len() - 2].to_vec();
+                        field_line[1].kind = TokenKind::Symbol(format!("{}.{}", name, field));
+                        field_line.push(line[line.len() - 1].clone());
+                        lowered.extend(field_line);
                     }
                 }
-                ':' => {
-                    tokens.push(Token {
-                        kind: TokenKind::Pin,
-                        pos: (y, x),
-                    });
+                match op {
+                    "arr" => self.record_arrays.insert(name, record),
+                    _ => self.record_vars.insert(name, record),
+                };
+            }
+            Some("var") => {
+                return Err(self.throw_at(
+                    CompileErrorKind::WrongArgumentCount(2, args.len()),
+                    &line,
+                    0,
+                ))
+            }
+            Some("mov") if args.len() == 2 && self.record_type(&line[1]).is_some() => {
+                let record = self.record_type(&line[1]).unwrap().clone();
+                if self.record_type(&line[2]) != Some(&record) {
+                    return Err(self.throw_at(CompileErrorKind::ExpectedRecord(record), &line, 2));
                 }
-                '"' => {
-                    tokens.push(Token {
-                        kind: TokenKind::Comment,
-                        pos: (y, x),
-                    });
+                for field in &self.records[&record] {
+                    let mut field_line = line.clone();
+                    for token in &mut field_line[1..3] {
+                        if let TokenKind::Symbol(symbol) = &token.kind {
+                            token.kind = TokenKind::Symbol(field_path(symbol, field));
+                        }
+                    }
+                    lowered.extend(field_line);
                 }
-                _ => {
-                    tokens.push(Token {
-                        kind: TokenKind::Symbol(c.to_string()),
-                        pos: (y, x),
-                    });
-                    return Err(self.throw_at(
-                        CompileErrorKind::UnexpectedChar(c),
-                        &tokens,
-                        tokens.len() - 1,
-                    ));
+            }
+            op => {
+                let is_label = matches!(line[0].kind, TokenKind::Pin)
+                    || op.is_some_and(|op| JUMP_OPS.contains(&op));
+                let mut line = line;
+                for i in 1..line.len() {
+                    if is_label && i == 1 {
+                        continue;
+                    }
+                    if let Some(symbol) = self.resolve_field(&line, i)? {
+                        line[i].kind = TokenKind::Symbol(symbol);
+                    }
                 }
+                lowered.extend(line);
             }
-            x += 1;
         }
-        if !symbol.is_empty() {
-            tokens.push(Token {
-                kind: TokenKind::Symbol(String::from_iter(symbol.clone())),
-                pos: (y, x),
-            });
-            symbol.clear();
+        Ok(())
+    }
+    fn record_type(&self, token: &Token) -> Option<&String> {
+        let symbol = match &token.kind {
+            TokenKind::Symbol(symbol) => symbol,
+            _ => return None,
+        };
+        match symbol.strip_suffix(']').and_then(|s| s.split_once('[')) {
+            Some((name, _)) => self.record_arrays.get(name),
+            None => self.record_vars.get(symbol),
         }
-        if !value.is_empty() {
-            tokens.push(Token {
-                kind: TokenKind::Value(String::from_iter(value.clone())),
-                pos: (y, x),
-            });
-            value.clear();
+    }
+    fn resolve_field(&self, line: &[Token], index: usize) -> CompileResult<Option<String>> {
+        let token = &line[index];
+        let symbol = match &token.kind {
+            TokenKind::Symbol(symbol) => symbol,
+            _ => return Ok(None),
+        };
+        if self.record_type(token).is_some() || self.record_arrays.contains_key(symbol) {
+            return Err(self.throw_at(CompileErrorKind::RecordValue(symbol.clone()), line, index));
         }
-        tokens.push(Token {
-            kind: TokenKind::Break,
-            pos: (y, x),
-        });
-        Ok(tokens)
+        let (base, field) = match symbol.rsplit_once('.') {
+            Some(access) => access,
+            None => return Ok(None),
+        };
+        let record = match base.strip_suffix(']').and_then(|s| s.split_once('[')) {
+            Some((name, _)) => unwrap_or_throw!(
+                self.record_arrays.get(name),
+                self.throw_at(CompileErrorKind::NotARecord(name.to_string()), line, index)
+            ),
+            None => match self.record_vars.get(base) {
+                Some(record) => record,
+                None => return Ok(None),
+            },
+        };
+        if !self.records[record].iter().any(|known| known == field) {
+            let kind = CompileErrorKind::UnknownField(record.clone(), field.to_string());
+            let Span { start, end } = token.span;
+            if end.offset.saturating_sub(start.offset) != symbol.len() {
+                return Err(self.throw_at(kind, line, index));
+            }
+            let start = Location {
+                offset: end.offset.saturating_sub(field.len()),
+                line: end.line,
+                column: end.column.saturating_sub(field.chars().count()),
+            };
+            let field_token = Token {
+                kind: TokenKind::Symbol(field.to_string()),
+                span: Span { start, end },
+                ..token.clone()
+            };
+            return Err(self.throw_at(kind, &[field_token], 0));
+        }
+        Ok(Some(field_path(base, field)))
     }
-    fn parse(&self, tokens: &Vec<Token>) -> Result<Vec<OpWrap>, CompileError> {
+    fn parse(&mut self, tokens: Vec<Token>) -> (Vec<Parsed>, Vec<Include>) {
         let mut ops = Vec::new();
+        let mut includes = Vec::new();
+        for line in split_lines(tokens) {
+            if let Err(err) = self.parse_line(&line, &mut ops, &mut includes) {
+                self.errors.push(*err);
+            }
+        }
+        (ops, includes)
+    }
+    fn parse_line(
+        &self,
+        line: &[Token],
+        ops: &mut Vec<Parsed>,
+        includes: &mut Vec<Include>,
+    ) -> CompileResult<()> {
         let mut pin = false;
         let mut pin_label = None;
+        let mut pin_params = Vec::new();
         let mut op_name = None;
         let mut op_args = Vec::new();
-        let mut comment = false;
+        let mut op_text = None;
         let mut tokens_in_line = Vec::new();
-        for token in tokens {
+        for token in line {
             tokens_in_line.push(token.clone());
             match &token.kind {
                 TokenKind::Pin => {
-                    if comment {
-                        continue;
-                    }
                     if op_name.is_some() {
                         return Err(self.throw_at(
                             CompileErrorKind::InvalidLocation,
@@ -179,18 +723,19 @@ impl Compiler {
                     pin = true;
                 }
                 TokenKind::Symbol(symbol) => {
-                    if comment {
-                        continue;
-                    }
                     if pin {
-                        if pin_label.is_some() {
+                        if pin_label.is_none() {
+                            pin_label = Some(symbol);
+                        } else if symbol.contains(['[', '.']) || self.constants.contains_key(symbol)
+                        {
                             return Err(self.throw_at(
-                                CompileErrorKind::InvalidLocation,
+                                CompileErrorKind::ExpectedPosition,
                                 &tokens_in_line,
-                                2,
+                                tokens_in_line.len() - 1,
                             ));
+                        } else {
+                            pin_params.push(symbol.clone());
                         }
-                        pin_label = Some(symbol);
                         continue;
                     }
                     if op_name.is_some() {
@@ -199,46 +744,46 @@ impl Compiler {
                     }
                     op_name = Some(symbol);
                 }
+                TokenKind::Value(_) | TokenKind::Cast(_) if pin => {
+                    return Err(self.throw_at(
+                        CompileErrorKind::ExpectedPosition,
+                        &tokens_in_line,
+                        tokens_in_line.len() - 1,
+                    ));
+                }
                 TokenKind::Value(value) => {
-                    if comment {
-                        continue;
-                    }
                     if op_name.is_some() {
                         op_args.push(value.clone());
                     }
                 }
                 TokenKind::Cast(value) => {
-                    if comment {
-                        continue;
-                    }
                     if op_name.is_some() {
-                        let bytes = value.as_bytes();
-                        if bytes.len() != 1 {
-                            return Err(self.throw_at(
+                        let value = unwrap_or_throw!(
+                            cast_value(value),
+                            self.throw_at(
                                 CompileErrorKind::InvalidCast,
                                 &tokens_in_line,
-                                0,
-                            ));
-                        }
-                        op_args.push(bytes[0].to_string());
+                                tokens_in_line.len() - 1
+                            )
+                        );
+                        op_args.push(value.to_string());
                     }
                 }
-                TokenKind::Comment => {
-                    comment = true;
+                TokenKind::Text(text) => {
+                    if op_name.is_some() {
+                        op_text = Some(text.clone());
+                    }
                 }
                 TokenKind::Break => {
-                    if comment {
-                        comment = false;
-                        tokens_in_line.clear();
-                        continue;
-                    }
                     if pin_label.is_some() {
                         let label = pin_label.take().unwrap();
-                        let op = Box::new(Pin(label.clone()));
-                        ops.push(OpWrap {
-                            op,
+                        let params = std::mem::take(&mut pin_params);
+                        let instruction = Instruction::Pin(self.qualify(label), params);
+                        ops.push(Parsed {
+                            instruction,
+                            notes: self.expansion_notes(tokens_in_line[0].expansion),
                             tokens: tokens_in_line.clone(),
-                            pre_init: true,
+                            source: self.source,
                         });
                         tokens_in_line.clear();
                         pin = false;
@@ -247,140 +792,189 @@ impl Compiler {
                     if op_name.is_some() {
                         let name = op_name.take().unwrap();
                         let mut args = op_args.drain(..);
-                        let op: Box<dyn Op> = match name.as_str() {
+                        if name == "inc" {
+                            let file = unwrap_or_throw!(
+                                op_text.take(),
+                                self.throw_at(CompileErrorKind::ExpectedText, &tokens_in_line, 0)
+                            );
+                            includes.push(Include {
+                                file,
+                                tokens: tokens_in_line.clone(),
+                            });
+                            tokens_in_line.clear();
+                            continue;
+                        }
+                        let instruction = match name.as_str() {
+                            "arr" => {
+                                let pos = self.expect_name(args.next(), &tokens_in_line)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Arr(pos, refer)
+                            }
+                            "new" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::New(pos, refer)
+                            }
+                            "del" => {
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Del(refer)
+                            }
+                            "loc" => {
+                                let pos = self.expect_name(args.next(), &tokens_in_line)?;
+                                Instruction::Loc(pos)
+                            }
                             "mov" => {
-                                let pos = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                let refer = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                Box::new(Mov(pos.to_string(), Ref::from_str(&refer)?))
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Mov(pos, refer)
                             }
                             "add" => {
-                                let pos = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                let refer = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                Box::new(Add(pos.to_string(), Ref::from_str(&&refer)?))
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Add(pos, refer)
                             }
                             "sub" => {
-                                let pos = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                let refer = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                Box::new(Sub(pos.to_string(), Ref::from_str(&refer)?))
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Sub(pos, refer)
+                            }
+                            "mul" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Mul(pos, refer)
+                            }
+                            "div" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Div(pos, refer)
+                            }
+                            "mod" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Mod(pos, refer)
+                            }
+                            "and" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::And(pos, refer)
+                            }
+                            "or" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Or(pos, refer)
+                            }
+                            "xor" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Xor(pos, refer)
+                            }
+                            "not" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                Instruction::Not(pos)
+                            }
+                            "shl" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Shl(pos, refer)
+                            }
+                            "shr" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Shr(pos, refer)
                             }
                             "cmp" => {
-                                let pos = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                let refer = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                Box::new(Cmp(pos.to_string(), Ref::from_str(&refer)?))
+                                let refer_a = self.expect_ref(args.next(), &tokens_in_line)?;
+                                let refer_b = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Cmp(refer_a, refer_b)
+                            }
+                            "flt" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                Instruction::Flt(pos)
+                            }
+                            "trn" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                Instruction::Trn(pos)
                             }
                             "jif" => {
-                                let label = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                Box::new(Jif(label.to_string()))
+                                let label = self.expect_arg(args.next(), &tokens_in_line)?;
+                                Instruction::Jif(self.qualify(&label))
                             }
                             "jel" => {
-                                let label = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                Box::new(Jel(label.to_string()))
+                                let label = self.expect_arg(args.next(), &tokens_in_line)?;
+                                Instruction::Jel(self.qualify(&label))
+                            }
+                            "jlt" => {
+                                let label = self.expect_arg(args.next(), &tokens_in_line)?;
+                                Instruction::Jlt(self.qualify(&label))
+                            }
+                            "jgt" => {
+                                let label = self.expect_arg(args.next(), &tokens_in_line)?;
+                                Instruction::Jgt(self.qualify(&label))
+                            }
+                            "jle" => {
+                                let label = self.expect_arg(args.next(), &tokens_in_line)?;
+                                Instruction::Jle(self.qualify(&label))
+                            }
+                            "jge" => {
+                                let label = self.expect_arg(args.next(), &tokens_in_line)?;
+                                Instruction::Jge(self.qualify(&label))
                             }
                             "jmp" => {
-                                let label = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0
-                                    )
-                                );
-                                Box::new(Jmp(label.to_string()))
+                                let label = self.expect_arg(args.next(), &tokens_in_line)?;
+                                Instruction::Jmp(self.qualify(&label))
+                            }
+                            "cal" => {
+                                let label = self.expect_arg(args.next(), &tokens_in_line)?;
+                                let mut refers = Vec::new();
+                                let mut target = None;
+                                while let Some(arg) = args.next() {
+                                    if arg == "->" {
+                                        let index = refers.len() + 3;
+                                        let pos =
+                                            self.expect_pos(args.next(), &tokens_in_line, index)?;
+                                        target = Some(pos);
+                                        break;
+                                    }
+                                    refers.push(self.to_ref(&arg)?);
+                                }
+                                Instruction::Cal(self.qualify(&label), refers, target)
                             }
                             "out" => {
-                                let refer = unwrap_or_throw!(
-                                    args.next(),
-                                    self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
-                                        &tokens_in_line,
-                                        0,
-                                    )
-                                );
-                                Box::new(Out(Ref::from_str(&refer)?))
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Out(refer)
                             }
                             "utf" => {
-                                let refer = unwrap_or_throw!(
-                                    args.next(),
+                                let refer = self.expect_ref(args.next(), &tokens_in_line)?;
+                                Instruction::Utf(refer)
+                            }
+                            "inp" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                Instruction::Inp(pos)
+                            }
+                            "get" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                Instruction::Get(pos)
+                            }
+                            "eof" => {
+                                let pos = self.expect_pos(args.next(), &tokens_in_line, 1)?;
+                                Instruction::Eof(pos)
+                            }
+                            "prt" => {
+                                let text = unwrap_or_throw!(
+                                    op_text.take(),
                                     self.throw_at(
-                                        CompileErrorKind::ExpectedArgument,
+                                        CompileErrorKind::ExpectedText,
                                         &tokens_in_line,
-                                        0,
+                                        0
                                     )
                                 );
-                                Box::new(Utf(Ref::from_str(&refer)?))
+                                Instruction::Prt(text)
                             }
-                            "ret" => Box::new(Ret),
-                            "end" => Box::new(End),
+                            "ret" => match args.next() {
+                                Some(arg) => Instruction::Ret(Some(self.to_ref(&arg)?)),
+                                None => Instruction::Ret(None),
+                            },
+                            "end" => Instruction::End,
                             _ => {
                                 return Err(self.throw_at(
                                     CompileErrorKind::UnknownOp,
@@ -389,32 +983,797 @@ impl Compiler {
                                 ))
                             }
                         };
-                        ops.push(OpWrap {
-                            op,
+                        ops.push(Parsed {
+                            instruction,
+                            notes: self.expansion_notes(tokens_in_line[0].expansion),
                             tokens: tokens_in_line.clone(),
-                            pre_init: false,
+                            source: self.source,
                         });
                         tokens_in_line.clear();
                         continue;
                     }
+                    tokens_in_line.clear();
                 }
             }
         }
-        Ok(ops)
+        Ok(())
+    }
+    fn qualify(&self, label: &str) -> String {
+        if self.prefix.is_empty() {
+            return label.to_string();
+        }
+        format!("{}.{}", self.prefix, label)
+    }
+    fn expect_arg(&self, arg: Option<String>, tokens: &[Token]) -> CompileResult<String> {
+        arg.ok_or_else(|| self.throw_at(CompileErrorKind::ExpectedArgument, tokens, 0))
+    }
+    fn expect_name(&self, arg: Option<String>, tokens: &[Token]) -> CompileResult<String> {
+        let arg = self.expect_arg(arg, tokens)?;
+        if self.constants.contains_key(&arg) {
+            return Err(self.throw_at(CompileErrorKind::ConstantAssignment(arg), tokens, 1));
+        }
+        Ok(arg)
+    }
+    fn expect_pos(
+        &self,
+        arg: Option<String>,
+        tokens: &[Token],
+        index: usize,
+    ) -> CompileResult<Ref> {
+        let arg = self.expect_arg(arg, tokens)?;
+        match self.to_ref(&arg)? {
+            Ref::Value(_) if self.constants.contains_key(&arg) => {
+                Err(self.throw_at(CompileErrorKind::ConstantAssignment(arg), tokens, index))
+            }
+            Ref::Value(_) => Err(self.throw_at(CompileErrorKind::ExpectedPosition, tokens, index)),
+            refer => Ok(refer),
+        }
+    }
+    fn expect_ref(&self, arg: Option<String>, tokens: &[Token]) -> CompileResult<Ref> {
+        let arg = self.expect_arg(arg, tokens)?;
+        self.to_ref(&arg)
+    }
+    fn to_ref(&self, arg: &str) -> CompileResult<Ref> {
+        Ok(self.substitute_constants(Ref::from_str(arg)?))
+    }
+    fn substitute_constants(&self, refer: Ref) -> Ref {
+        match refer {
+            Ref::Pos(pos) => match self.constants.get(&pos) {
+                Some(value) => Ref::Value(*value),
+                None => Ref::Pos(pos),
+            },
+            Ref::Index(pos, index) => Ref::Index(pos, Box::new(self.substitute_constants(*index))),
+            Ref::Deref(pointer) => Ref::Deref(Box::new(self.substitute_constants(*pointer))),
+            refer => refer,
+        }
+    }
+    fn file(&self) -> Option<String> {
+        self.path.as_ref().map(|path| path.display().to_string())
     }
-    pub fn throw_at(&self, kind: CompileErrorKind, tokens: &[Token], index: usize) -> CompileError {
+    pub fn throw_at(
+        &self,
+        kind: CompileErrorKind,
+        tokens: &[Token],
+        index: usize,
+    ) -> Box<CompileError> {
         let token = &tokens[index];
-        let (y, _) = token.clone().pos;
-        let lines = self.raw.lines().collect::<Vec<_>>();
-        let length = match &token.kind {
-            TokenKind::Symbol(symbol) => symbol.len(),
-            _ => 1,
-        };
-        CompileError {
+        self.throw_span(kind, token.span, token.expansion)
+    }
+    fn throw_span(
+        &self,
+        kind: CompileErrorKind,
+        span: Span,
+        expansion: Option<usize>,
+    ) -> Box<CompileError> {
+        Box::new(CompileError {
             kind,
-            line: lines[y].to_string(),
-            pos: token.clone().pos,
-            len: length,
+            file: self.file(),
+            line: self.line(span),
+            span,
+            notes: self.expansion_notes(expansion),
+        })
+    }
+    fn expansion_notes(&self, mut expansion: Option<usize>) -> Vec<Note> {
+        let mut notes = Vec::new();
+        while let Some(index) = expansion {
+            let Expansion { name, call } = &self.expansions[index];
+            notes.push(self.note_at(format!("In expansion of macro '{}'", name), call));
+            expansion = call.expansion;
+        }
+        notes
+    }
+    fn note_at(&self, message: String, token: &Token) -> Note {
+        Note {
+            message,
+            file: self.file(),
+            line: self.line(token.span),
+            span: token.span,
+        }
+    }
+    fn line(&self, span: Span) -> String {
+        self.raw
+            .lines()
+            .nth(span.start.line)
+            .unwrap_or("")
+            .to_string()
+    }
+}
+
+type Pins = HashMap<Label, (usize, Vec<Pos>)>;
+
+fn resolve_pins(
+    sources: &[Source],
+    parsed: &[Parsed],
+    errors: &mut Vec<(usize, CompileError)>,
+) -> Pins {
+    let mut pins = Pins::new();
+    for (index, op) in parsed.iter().enumerate() {
+        if let Instruction::Pin(label, params) = &op.instruction {
+            if let Some((first, _)) = pins.get(label) {
+                let mut err = error_at(
+                    CompileErrorKind::DuplicatePin(label.clone()),
+                    sources,
+                    op,
+                    1,
+                );
+                err.notes.push(note_at(
+                    format!("Pin '{}' is first placed here", label),
+                    sources,
+                    &parsed[*first],
+                    1,
+                ));
+                errors.push((op.source, *err));
+                continue;
+            }
+            pins.insert(label.clone(), (index, params.clone()));
+        }
+    }
+    for op in parsed {
+        match op.instruction.label() {
+            Some(label) if !pins.contains_key(label) => {
+                let err = error_at(CompileErrorKind::NoPin(label.clone()), sources, op, 1);
+                errors.push((op.source, *err));
+            }
+            _ => (),
+        }
+    }
+    pins
+}
+
+fn check_calls(
+    sources: &[Source],
+    parsed: &[Parsed],
+    pins: &Pins,
+    errors: &mut Vec<(usize, CompileError)>,
+) {
+    for op in parsed {
+        let (label, args) = match &op.instruction {
+            Instruction::Cal(label, args, _) => (label, args.len()),
+            _ => continue,
+        };
+        let (index, params) = match pins.get(label) {
+            Some(pin) => pin,
+            None => continue,
+        };
+        if params.len() != args {
+            let mut err = error_at(
+                CompileErrorKind::WrongArgumentCount(params.len(), args),
+                sources,
+                op,
+                1,
+            );
+            err.notes.push(note_at(
+                format!("Subroutine '{}' is declared here", label),
+                sources,
+                &parsed[*index],
+                1,
+            ));
+            errors.push((op.source, *err));
+        }
+    }
+}
+
+fn check_returns(sources: &[Source], parsed: &[Parsed], pins: &Pins) -> Vec<CompileWarning> {
+    let mut visited = vec![false; parsed.len()];
+    let mut queue = vec![(0, None)];
+    let mut jumps = Vec::new();
+    while let Some((index, via)) = queue.pop() {
+        if index >= parsed.len() || visited[index] {
+            continue;
+        }
+        visited[index] = true;
+        let op = &parsed[index];
+        let target = op.instruction.label().map(|label| pins[label].0);
+        match op.instruction.name() {
+            "ret" => {
+                if let Some(via) = via {
+                    if !jumps.contains(&via) {
+                        jumps.push(via);
+                    }
+                }
+            }
+            "end" => (),
+            "jmp" => queue.extend(target.map(|target| (target, Some(index)))),
+            "jif" | "jel" | "jlt" | "jgt" | "jle" | "jge" => {
+                queue.push((index + 1, via));
+                queue.extend(target.map(|target| (target, Some(index))));
+            }
+            _ => queue.push((index + 1, via)),
+        }
+    }
+    jumps.sort();
+    jumps
+        .into_iter()
+        .map(|index| {
+            let op = &parsed[index];
+            let label = op.instruction.label().cloned().unwrap_or_default();
+            warning_at(CompileWarningKind::ReturnAfterJump(label), sources, op, 1)
+        })
+        .collect()
+}
+
+#[derive(Clone, Default)]
+struct Definitions {
+    may: HashSet<Pos>,
+    must: HashSet<Pos>,
+}
+
+impl Definitions {
+    fn define(&mut self, pos: &Pos) {
+        self.may.insert(pos.clone());
+        self.must.insert(pos.clone());
+    }
+    fn remove(&mut self, pos: &Pos) {
+        self.may.remove(pos);
+        self.must.remove(pos);
+    }
+    fn lookup(&self, pos: &Pos) -> (bool, bool) {
+        (self.may.contains(pos), self.must.contains(pos))
+    }
+    fn merge(&mut self, other: &Definitions) -> bool {
+        let (may, must) = (self.may.len(), self.must.len());
+        self.may.extend(other.may.iter().cloned());
+        self.must.retain(|pos| other.must.contains(pos));
+        self.may.len() != may || self.must.len() != must
+    }
+}
+
+/// The definitions visible at an operation, split like the runtime splits
+/// global memory and the locals of the innermost frame.
+#[derive(Clone, Default)]
+struct Scope {
+    globals: Definitions,
+    declared: Definitions,
+    locals: Definitions,
+}
+
+impl Scope {
+    fn frame(globals: &Definitions, params: &[Pos]) -> Self {
+        let mut locals = Definitions::default();
+        for param in params {
+            locals.define(param);
         }
+        Self {
+            globals: globals.clone(),
+            declared: locals.clone(),
+            locals,
+        }
+    }
+    fn declare(&mut self, pos: &Pos) {
+        self.declared.define(pos);
+        self.locals.remove(pos);
+    }
+    fn define(&mut self, pos: &Pos, certain: bool) {
+        let definitions = match self.declared.lookup(pos) {
+            (_, true) => &mut self.locals,
+            (false, _) => &mut self.globals,
+            _ => {
+                self.locals.may.insert(pos.clone());
+                self.globals.may.insert(pos.clone());
+                return;
+            }
+        };
+        definitions.may.insert(pos.clone());
+        if certain {
+            definitions.must.insert(pos.clone());
+        }
+    }
+    fn lookup(&self, pos: &Pos) -> (bool, bool) {
+        let (local, global) = (self.locals.lookup(pos), self.globals.lookup(pos));
+        match self.declared.lookup(pos) {
+            (_, true) => local,
+            (false, _) => global,
+            _ => (local.0 || global.0, local.1 && global.1),
+        }
+    }
+    fn merge(state: &mut Option<Scope>, other: &Scope) -> bool {
+        match state {
+            Some(state) => {
+                let globals = state.globals.merge(&other.globals);
+                let declared = state.declared.merge(&other.declared);
+                state.locals.merge(&other.locals) || globals || declared
+            }
+            None => {
+                *state = Some(other.clone());
+                true
+            }
+        }
+    }
+}
+
+fn check_definitions(
+    sources: &[Source],
+    parsed: &[Parsed],
+    pins: &Pins,
+    errors: &mut Vec<(usize, CompileError)>,
+) -> Vec<CompileWarning> {
+    let target = |op: &Parsed| op.instruction.label().map(|label| pins[label].0);
+    let mut returns = HashMap::new();
+    for op in parsed {
+        if let Instruction::Cal(label, _, _) = &op.instruction {
+            let pin = pins[label].0;
+            returns
+                .entry(pin)
+                .or_insert_with(|| subroutine_returns(parsed, pins, pin));
+        }
+    }
+    let mut states: Vec<Option<Scope>> = vec![None; parsed.len()];
+    if let Some(state) = states.first_mut() {
+        *state = Some(Scope::default());
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (index, op) in parsed.iter().enumerate() {
+            let mut state = match &states[index] {
+                Some(state) => state.clone(),
+                None => continue,
+            };
+            match &op.instruction {
+                Instruction::Loc(pos) => state.declare(pos),
+                Instruction::Inp(Ref::Pos(pos)) => state.define(pos, false),
+                instruction => {
+                    for pos in instruction.defines() {
+                        state.define(pos, true);
+                    }
+                }
+            }
+            let mut next = Vec::new();
+            match &op.instruction {
+                Instruction::Cal(label, _, refer) => {
+                    let (target, params) = &pins[label];
+                    let mut returned = None;
+                    for ret in &returns[target] {
+                        if let Some(ret) = &states[*ret] {
+                            Scope::merge(&mut returned, ret);
+                        }
+                    }
+                    if let Some(returned) = returned {
+                        let mut after = state.clone();
+                        after.globals.may.extend(returned.globals.may);
+                        after.globals.must.extend(returned.globals.must);
+                        if let Some(Ref::Pos(pos)) = refer {
+                            after.define(pos, true);
+                        }
+                        next.push((index + 1, after));
+                    }
+                    next.push((*target, Scope::frame(&state.globals, params)));
+                }
+                instruction => match instruction.name() {
+                    "ret" | "end" => (),
+                    "jmp" => next.extend(target(op).map(|target| (target, state))),
+                    "jif" | "jel" | "jlt" | "jgt" | "jle" | "jge" => {
+                        next.extend(target(op).map(|target| (target, state.clone())));
+                        next.push((index + 1, state));
+                    }
+                    _ => next.push((index + 1, state)),
+                },
+            }
+            for (successor, state) in next {
+                if successor < parsed.len() {
+                    changed |= Scope::merge(&mut states[successor], &state);
+                }
+            }
+        }
+    }
+    let mut warnings = Vec::new();
+    for (op, state) in parsed.iter().zip(&states) {
+        let state = match state {
+            Some(state) => state,
+            None => continue,
+        };
+        let mut reported = Vec::new();
+        for (arg, pos) in op.instruction.reads() {
+            if reported.contains(&pos) {
+                continue;
+            }
+            reported.push(pos);
+            match state.lookup(pos) {
+                (false, _) => {
+                    let kind = CompileErrorKind::UndefinedRead(pos.clone());
+                    errors.push((op.source, *error_at(kind, sources, op, arg)));
+                }
+                (true, false) => {
+                    let kind = CompileWarningKind::MaybeUndefined(pos.clone());
+                    warnings.push(warning_at(kind, sources, op, arg));
+                }
+                (true, true) => (),
+            }
+        }
+    }
+    warnings
+}
+
+fn subroutine_returns(parsed: &[Parsed], pins: &Pins, pin: usize) -> Vec<usize> {
+    let mut visited = vec![false; parsed.len()];
+    let mut queue = vec![pin];
+    let mut returns = Vec::new();
+    while let Some(index) = queue.pop() {
+        if index >= parsed.len() || visited[index] {
+            continue;
+        }
+        visited[index] = true;
+        let instruction = &parsed[index].instruction;
+        let target = instruction.label().map(|label| pins[label].0);
+        match instruction.name() {
+            "ret" => returns.push(index),
+            "end" => (),
+            "jmp" => queue.extend(target),
+            "jif" | "jel" | "jlt" | "jgt" | "jle" | "jge" => {
+                queue.push(index + 1);
+                queue.extend(target);
+            }
+            _ => queue.push(index + 1),
+        }
+    }
+    returns
+}
+
+fn error_at(
+    kind: CompileErrorKind,
+    sources: &[Source],
+    op: &Parsed,
+    index: usize,
+) -> Box<CompileError> {
+    let source = &sources[op.source];
+    let token = &op.tokens[index];
+    Box::new(CompileError {
+        kind,
+        file: source.file(),
+        line: source.line(token.span),
+        span: token.span,
+        notes: op.notes.clone(),
+    })
+}
+
+fn warning_at(
+    kind: CompileWarningKind,
+    sources: &[Source],
+    op: &Parsed,
+    index: usize,
+) -> CompileWarning {
+    let source = &sources[op.source];
+    let token = &op.tokens[index];
+    CompileWarning {
+        kind,
+        file: source.file(),
+        line: source.line(token.span),
+        span: token.span,
+    }
+}
+
+fn note_at(message: String, sources: &[Source], op: &Parsed, index: usize) -> Note {
+    let source = &sources[op.source];
+    let token = &op.tokens[index];
+    Note {
+        message,
+        file: source.file(),
+        line: source.line(token.span),
+        span: token.span,
+    }
+}
+
+type CompileResult<T> = Result<T, Box<CompileError>>;
+
+const JUMP_OPS: [&str; 8] = ["jmp", "jif", "jel", "jlt", "jgt", "jle", "jge", "cal"];
+
+fn split_lines(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut lines = Vec::new();
+    let mut line = Vec::new();
+    for token in tokens {
+        let is_break = matches!(token.kind, TokenKind::Break);
+        line.push(token);
+        if is_break {
+            lines.push(line);
+            line = Vec::new();
+        }
+    }
+    lines
+}
+
+fn module_name(file: &str) -> String {
+    Path::new(file)
+        .with_extension("")
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            Component::ParentDir => Some("super".to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn substitute(symbol: &str, params: &[String], args: &[&Token]) -> String {
+    let arg = |name: &str| {
+        let param = params.iter().position(|param| param == name)?;
+        Some(&args[param].kind)
+    };
+    if let Some(pointer) = symbol.strip_prefix('*') {
+        return format!("*{}", substitute(pointer, params, args));
+    }
+    if let Some((base, field)) = symbol.rsplit_once('.') {
+        return format!("{}.{}", substitute(base, params, args), field);
+    }
+    if let Some((pos, index)) = symbol.strip_suffix(']').and_then(|s| s.split_once('[')) {
+        let index = match arg(index) {
+            Some(TokenKind::Symbol(arg) | TokenKind::Value(arg)) => arg.as_str(),
+            _ => index,
+        };
+        return format!("{}[{}]", substitute(pos, params, args), index);
+    }
+    match arg(symbol) {
+        Some(TokenKind::Symbol(arg)) => arg.clone(),
+        _ => symbol.to_string(),
+    }
+}
+
+fn block_label(name: &str, id: usize) -> String {
+    format!("#{}{}", name, id)
+}
+
+fn label_token(at: &Token, name: &str, id: usize) -> Token {
+    Token {
+        kind: TokenKind::Symbol(block_label(name, id)),
+        ..at.clone()
+    }
+}
+
+fn close_block(at: &Token, block: &Block) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    if block.keyword == "while" {
+        tokens.extend(lower_op(
+            at,
+            "jmp",
+            vec![label_token(at, "while", block.id)],
+        ));
+        tokens.extend(lower_pin(at, block_label("done", block.id)));
+        return tokens;
+    }
+    if !block.has_else {
+        tokens.extend(lower_pin(at, block_label("else", block.id)));
+    }
+    tokens.extend(lower_pin(at, block_label("fi", block.id)));
+    tokens
+}
+
+fn lower_op(at: &Token, name: &str, args: Vec<Token>) -> Vec<Token> {
+    let mut line = vec![Token {
+        kind: TokenKind::Symbol(name.to_string()),
+        ..at.clone()
+    }];
+    line.extend(args);
+    line.push(Token {
+        kind: TokenKind::Break,
+        ..at.clone()
+    });
+    line
+}
+
+fn lower_pin(at: &Token, label: String) -> Vec<Token> {
+    vec![
+        Token {
+            kind: TokenKind::Pin,
+            ..at.clone()
+        },
+        Token {
+            kind: TokenKind::Symbol(label),
+            ..at.clone()
+        },
+        Token {
+            kind: TokenKind::Break,
+            ..at.clone()
+        },
+    ]
+}
+
+fn field_path(symbol: &str, field: &str) -> String {
+    match symbol.strip_suffix(']').and_then(|s| s.split_once('[')) {
+        Some((name, index)) => format!("{}.{}[{}]", name, field, index),
+        None => format!("{}.{}", symbol, field),
+    }
+}
+
+fn op_symbol(line: &[Token]) -> Option<&str> {
+    match &line.first()?.kind {
+        TokenKind::Symbol(symbol) => Some(symbol),
+        _ => None,
+    }
+}
+
+fn line_args(line: &[Token]) -> Vec<&Token> {
+    line.iter()
+        .skip(1)
+        .take_while(|token| !matches!(token.kind, TokenKind::Break))
+        .collect()
+}
+
+fn cast_value(cast: &str) -> Option<Value> {
+    let mut chars = cast.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(Value::Int(c as Int)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile_errors(raw: &str) -> Vec<CompileError> {
+        match Compiler::default().compile(raw) {
+            Ok(_) => Vec::new(),
+            Err(CompileErrors(errors)) => errors,
+        }
+    }
+
+    fn errors(raw: &str) -> Vec<CompileErrorKind> {
+        compile_errors(raw)
+            .into_iter()
+            .map(|err| err.kind)
+            .collect()
+    }
+
+    fn undefined(raw: &str) -> (Vec<Pos>, Vec<Pos>) {
+        let exe = match Compiler::default().compile(raw) {
+            Ok(exe) => exe,
+            Err(CompileErrors(errors)) => {
+                let errors = errors.into_iter().filter_map(|err| match err.kind {
+                    CompileErrorKind::UndefinedRead(pos) => Some(pos),
+                    _ => None,
+                });
+                return (errors.collect(), Vec::new());
+            }
+        };
+        let warnings = exe
+            .warnings
+            .into_iter()
+            .filter_map(|warning| match warning.kind {
+                CompileWarningKind::MaybeUndefined(pos) => Some(pos),
+                _ => None,
+            });
+        (Vec::new(), warnings.collect())
+    }
+
+    #[test]
+    fn reports_duplicate_pin_with_first_placement() {
+        let errors = compile_errors(":a\njmp a\n:a\nend");
+        assert!(
+            matches!(&errors[..], [err] if matches!(&err.kind, CompileErrorKind::DuplicatePin(label) if label == "a"))
+        );
+        assert_eq!(errors[0].span.start.line, 2);
+        assert_eq!(errors[0].notes.len(), 1);
+        assert_eq!(errors[0].notes[0].message, "Pin 'a' is first placed here");
+        assert_eq!(errors[0].notes[0].span.start.line, 0);
+    }
+
+    #[test]
+    fn reports_unknown_jump_target() {
+        let errors = compile_errors(":loop\njel lop\ncal loop 1");
+        assert!(matches!(&errors[0].kind, CompileErrorKind::NoPin(label) if label == "lop"));
+        assert_eq!(errors[0].span.start.column, 4);
+        assert!(matches!(
+            errors[1].kind,
+            CompileErrorKind::WrongArgumentCount(0, 1)
+        ));
+    }
+
+    #[test]
+    fn collects_errors_in_source_order() {
+        let errors = compile_errors("mov a $\nfoo a\nout\njmp nowhere\nmov a 0x1G");
+        let lines: Vec<_> = errors.iter().map(|err| err.span.start.line).collect();
+        assert_eq!(lines, [0, 1, 2, 3, 4]);
+        assert!(matches!(
+            errors[0].kind,
+            CompileErrorKind::UnexpectedChar('$')
+        ));
+        assert!(matches!(errors[1].kind, CompileErrorKind::UnknownOp));
+        assert!(matches!(errors[2].kind, CompileErrorKind::ExpectedArgument));
+        assert!(matches!(errors[3].kind, CompileErrorKind::NoPin(_)));
+        assert!(matches!(
+            errors[4].kind,
+            CompileErrorKind::InvalidDigit('G', 16)
+        ));
+        let summary = CompileErrors(errors).to_string();
+        assert!(summary.contains("Compilation failed with 5 errors!"));
+    }
+
+    #[test]
+    fn reports_unknown_field_from_macro_argument() {
+        let raw = "def m v\nout v\nenddef\nrec pt x y\nvar a pt\nm a.verylongfieldname";
+        assert!(matches!(
+            errors(raw)[..],
+            [CompileErrorKind::UnknownField(ref record, ref field)]
+                if record == "pt" && field == "verylongfieldname"
+        ));
+    }
+
+    #[test]
+    fn rejects_float_constant_as_index() {
+        let raw = "const F 1.5\narr b 2\nout b[F]";
+        assert!(matches!(errors(raw)[..], [CompileErrorKind::InvalidIndex]));
+    }
+
+    #[test]
+    fn reports_call_target_at_its_token() {
+        let raw = "const N 1\ncal f 2 -> N\ncal f 2 -> 5\nend\n:f x\nret x";
+        let Err(CompileErrors(errors)) = Compiler::default().compile(raw) else {
+            panic!("expected compile errors");
+        };
+        assert!(matches!(
+            errors[0].kind,
+            CompileErrorKind::ConstantAssignment(_)
+        ));
+        assert!(matches!(errors[1].kind, CompileErrorKind::ExpectedPosition));
+        for err in &errors {
+            assert_eq!(err.span.start.column, 11);
+        }
+    }
+
+    #[test]
+    fn compiles_twice_with_one_compiler() {
+        let mut compiler = Compiler::default();
+        let raw = "const N 1\nrec p x\ndef m a\n  out a\nenddef\nvar v p\nmov v.x N\nm v.x";
+        assert!(compiler.compile(raw).is_ok());
+        assert!(compiler.compile(raw).is_ok());
+    }
+
+    #[test]
+    fn names_modules_by_relative_path() {
+        assert_eq!(module_name("util.ask"), "util");
+        assert_eq!(module_name("./lib/util.ask"), "lib.util");
+        assert_eq!(module_name("../util.ask"), "super.util");
+    }
+
+    #[test]
+    fn reads_globals_through_nested_calls() {
+        let raw = "mov g 1\ncal b\nend\n:b\nloc g\ncal sub\nret\n:sub\nout g\nret";
+        assert_eq!(undefined(raw), (vec![], vec![]));
+    }
+
+    #[test]
+    fn forgets_locals_after_return() {
+        let raw = "cal f\nout tmp\nend\n:f\nloc tmp\nmov tmp 1\nret";
+        assert_eq!(undefined(raw), (vec!["tmp".to_string()], vec![]));
+    }
+
+    #[test]
+    fn keeps_globals_written_by_subroutine() {
+        let raw = "cal f\nout x\nend\n:f\nmov x 2\nret";
+        assert_eq!(undefined(raw), (vec![], vec![]));
+    }
+
+    #[test]
+    fn binds_params_only_on_call() {
+        let raw = "cal sq 4 -> r\nout r\nend\n:sq n\nmul n n\nret n";
+        assert_eq!(undefined(raw), (vec![], vec![]));
+        assert_eq!(undefined(":sq n\nmul n n"), (vec!["n".to_string()], vec![]));
+    }
+
+    #[test]
+    fn warns_on_partial_definitions() {
+        let raw = "mov c 1\nif c == 0\n  mov a 1\nfi\nout a";
+        assert_eq!(undefined(raw), (vec![], vec!["a".to_string()]));
+        assert_eq!(undefined("inp a\nout a"), (vec![], vec!["a".to_string()]));
     }
 }
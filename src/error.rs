@@ -2,46 +2,157 @@ use std::fmt;
 
 use crate::color;
 use crate::Color;
+use crate::Int;
 use crate::Label;
 use crate::Pos;
+use crate::Span;
+use crate::Value;
 
 #[derive(Debug)]
 pub struct CompileError {
     pub kind: CompileErrorKind,
+    pub file: Option<String>,
     pub line: String,
-    pub pos: (usize, usize),
-    pub len: usize,
+    pub span: Span,
+    pub notes: Vec<Note>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub message: String,
+    pub file: Option<String>,
+    pub line: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        diagnostic(
+            f,
+            ("Note", Color::BrightCyan),
+            &self.message,
+            self.file.as_deref(),
+            &self.line,
+            self.span,
+        )
+    }
 }
 
 impl fmt::Display for CompileError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use CompileErrorKind::*;
-        let (y, x) = self.pos;
-        let message = match self.kind {
+        let message = match &self.kind {
             UnexpectedChar(c) => format!("Unexpected character '{}'!", c),
             InvalidLocation => "Invalid location!".to_string(),
             UnknownOp => "Unknown operation!".to_string(),
             ExpectedArgument => "Expected argument!".to_string(),
             InvalidBlock => "Invalid block!".to_string(),
             InvalidCast => "Invalid cast!".to_string(),
+            InvalidIndex => "Invalid index!".to_string(),
+            ExpectedPosition => "Expected position!".to_string(),
+            ExpectedText => "Expected text!".to_string(),
+            InvalidEscape => "Invalid escape sequence!".to_string(),
+            UnterminatedText => "Unterminated text!".to_string(),
+            UnterminatedComment => "Unterminated block comment!".to_string(),
+            MissingFile(file, reason) => {
+                format!("Unable to include file '{}'! Reason: {}", file, reason)
+            }
+            CyclicInclude(file) => format!("Cyclic include of file '{}'!", file),
+            ModuleCollision(module) => {
+                format!(
+                    "Module name '{}' is already used by another include!",
+                    module
+                )
+            }
+            UnterminatedMacro(name) => format!("Macro '{}' is missing 'enddef'!", name),
+            RecursiveMacro(name) => format!("Macro '{}' expands itself!", name),
+            ExpectedValue => "Expected value!".to_string(),
+            InvalidLiteral(literal) => format!("Invalid number '{}'!", literal),
+            InvalidDigit(c, radix) => format!("Invalid digit '{}' for a base {} number!", c, radix),
+            InvalidSeparator(literal) => {
+                format!("Misplaced digit separator in number '{}'!", literal)
+            }
+            LiteralOverflow(literal) => format!("Number '{}' does not fit into a value!", literal),
+            DuplicateConstant(name) => format!("Constant '{}' is already defined!", name),
+            ConstantAssignment(name) => format!("Cannot assign to constant '{}'!", name),
+            NoPin(label) => format!("No pin with name '{}' found!", label),
+            DuplicatePin(label) => format!("Pin with name '{}' already in use!", label),
+            InvalidCondition => "Invalid condition! Use '[a] [==|!=|<|>|<=|>=] [b]'.".to_string(),
+            UnclosedBlock(keyword) => format!("Block '{}' is never closed!", keyword),
+            UnmatchedBlock(keyword) => format!("'{}' has no matching block!", keyword),
+            DuplicateRecord(name) => format!("Record '{}' is already defined!", name),
+            DuplicateField(name) => format!("Field '{}' is already defined!", name),
+            UnknownRecord(name) => format!("Record '{}' is not defined!", name),
+            UnknownField(record, field) => {
+                format!("Record '{}' has no field '{}'!", record, field)
+            }
+            NotARecord(name) => format!("'{}' is not an array of records!", name),
+            ExpectedRecord(name) => format!("Expected a '{}' record!", name),
+            RecordValue(name) => format!("Record '{}' can only be moved as a whole!", name),
+            WrongArgumentCount(expected, found) => {
+                format!("Expected {} arguments but found {}!", expected, found)
+            }
+            UndefinedRead(pos) => format!("'{}' is read before it is defined!", pos),
         };
-        write!(
+        diagnostic(
             f,
-            "\n{}: {}\n {: <digit$} {}\n{} {}\n {: <digit$} {} {} {}\n",
-            color!("CompileError", Color::BrightRed),
-            color!(message, Color::BrightWhite),
-            "",
-            color!("|", Color::BrightBlue),
-            color!(format!(" {} |", y + 1), Color::BrightBlue),
-            self.line,
-            "",
-            color!("|", Color::BrightBlue),
-            color!(
-                format!("{: >width$}", "^".repeat(self.len), width = x - 1),
-                Color::BrightRed
+            ("CompileError", Color::BrightRed),
+            &message,
+            self.file.as_deref(),
+            &self.line,
+            self.span,
+        )?;
+        for note in &self.notes {
+            write!(f, "{}", note)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct CompileErrors(pub Vec<CompileError>);
+
+impl fmt::Display for CompileErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for err in &self.0 {
+            write!(f, "{}", err)?;
+        }
+        let count = self.0.len();
+        let summary = match count {
+            1 => "Compilation failed with 1 error!".to_string(),
+            _ => format!("Compilation failed with {} errors!", count),
+        };
+        write!(f, "\n{}", color!(summary, Color::BrightRed))
+    }
+}
+
+#[derive(Debug)]
+pub struct CompileWarning {
+    pub kind: CompileWarningKind,
+    pub file: Option<String>,
+    pub line: String,
+    pub span: Span,
+}
+
+impl fmt::Display for CompileWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CompileWarningKind::*;
+        let message = match &self.kind {
+            ReturnAfterJump(label) => format!(
+                "'ret' is reached after a plain jump to '{}'! Use 'cal' to jump to subroutines.",
+                label
             ),
-            color!(message, Color::BrightRed),
-            digit = (y + 1).to_string().len(),
+            MaybeUndefined(pos) => {
+                format!("'{}' might be read before it is defined!", pos)
+            }
+        };
+        diagnostic(
+            f,
+            ("CompileWarning", Color::BrightYellow),
+            &message,
+            self.file.as_deref(),
+            &self.line,
+            self.span,
         )
     }
 }
@@ -49,41 +160,53 @@ impl fmt::Display for CompileError {
 #[derive(Debug)]
 pub struct RuntimeError {
     pub kind: RuntimeErrorKind,
+    pub file: Option<String>,
     pub line: String,
-    pub loc: (usize, usize),
-    pub len: usize,
+    pub span: Span,
 }
 
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use RuntimeErrorKind::*;
-        let (y, x) = self.loc;
         let message = match &self.kind {
-            Undefined(key) => format!("'{}' is not defined!", key),
+            Undefined(key) => format!("Global '{}' is not defined!", key),
+            UndefinedLocal(key) => format!("Local '{}' is not defined!", key),
             NoCompare => "'cmp' operation before expected!".to_string(),
-            NoPin(label) => format!("No pin with name '{}' found!", label),
-            DuplicatePin(label) => {
-                format!("Pin with name '{}' already in use!", label)
-            }
             NoReturn => "No pin to jump back to!".to_string(),
+            NoReturnValue => "'ret' needs a value for the calling 'cal'!".to_string(),
+            NoFrame => "'loc' can only be used inside a subroutine!".to_string(),
+            DivisionByZero => "Division by zero!".to_string(),
+            Overflow => "Arithmetic overflow!".to_string(),
+            Underflow => "Arithmetic underflow!".to_string(),
+            InvalidShift(amount) => format!("Cannot shift by {} bits!", amount),
+            InvalidSize(size) => format!("Cannot create array with size {}!", size),
+            OutOfBounds(pos, index) => format!("Index {} is out of bounds for '{}'!", index, pos),
+            UndefinedElement(pos, index) => format!("'{}[{}]' is not defined!", pos, index),
+            IncompatibleOperand(_, value) => {
+                format!("Operation only supports integers but found {}!", value)
+            }
+            FloatIndex(pos, index) => format!("Index {} for '{}' is not an integer!", index, pos),
+            InvalidAllocation(size) => format!("Cannot allocate a block with size {}!", size),
+            InvalidAddress(address) => format!("Address {} is not allocated!", address),
+            UndefinedAddress(address) => format!("Address {} is not defined!", address),
+            UseAfterFree(address) => format!("Address {} was already freed!", address),
+            DoubleFree(address) => format!("Block at address {} was already freed!", address),
+            InvalidFree(address) => {
+                format!(
+                    "Address {} is not the start of an allocated block!",
+                    address
+                )
+            }
+            InvalidInput(input) => format!("Input '{}' is not a number!", input),
+            ReadFailed(reason) => format!("Unable to read input! Reason: {}", reason),
         };
-        write!(
+        diagnostic(
             f,
-            "\n{}: {}\n {: <digit$} {}\n{} {}\n {: <digit$} {} {} {}\n",
-            color!("RuntimeError", Color::BrightRed),
-            color!(message, Color::BrightWhite),
-            "",
-            color!("|", Color::BrightBlue),
-            color!(format!(" {} |", y + 1), Color::BrightBlue),
-            self.line,
-            "",
-            color!("|", Color::BrightBlue),
-            color!(
-                format!("{: >width$}", "^".repeat(self.len), width = x - 1),
-                Color::BrightRed
-            ),
-            color!(message, Color::BrightRed),
-            digit = (y + 1).to_string().len(),
+            ("RuntimeError", Color::BrightRed),
+            &message,
+            self.file.as_deref(),
+            &self.line,
+            self.span,
         )
     }
 }
@@ -99,13 +222,115 @@ pub enum CompileErrorKind {
     ExpectedArgument,
     InvalidBlock,
     InvalidCast,
+    InvalidIndex,
+    ExpectedPosition,
+    ExpectedText,
+    InvalidEscape,
+    UnterminatedText,
+    UnterminatedComment,
+    MissingFile(String, String),
+    CyclicInclude(String),
+    ModuleCollision(String),
+    UnterminatedMacro(String),
+    RecursiveMacro(String),
+    WrongArgumentCount(usize, usize),
+    UndefinedRead(Pos),
+    ExpectedValue,
+    InvalidLiteral(String),
+    InvalidDigit(char, u32),
+    InvalidSeparator(String),
+    LiteralOverflow(String),
+    DuplicateConstant(String),
+    ConstantAssignment(String),
+    NoPin(Label),
+    DuplicatePin(Label),
+    InvalidCondition,
+    UnclosedBlock(String),
+    UnmatchedBlock(String),
+    DuplicateRecord(String),
+    DuplicateField(String),
+    UnknownRecord(String),
+    UnknownField(String, String),
+    NotARecord(String),
+    ExpectedRecord(String),
+    RecordValue(String),
+}
+
+#[derive(Debug)]
+pub enum CompileWarningKind {
+    ReturnAfterJump(Label),
+    MaybeUndefined(Pos),
 }
 
 #[derive(Debug)]
 pub enum RuntimeErrorKind {
     Undefined(Pos),
+    UndefinedLocal(Pos),
     NoCompare,
-    NoPin(Label),
-    DuplicatePin(Label),
     NoReturn,
+    NoReturnValue,
+    NoFrame,
+    DivisionByZero,
+    Overflow,
+    Underflow,
+    InvalidShift(Int),
+    InvalidSize(Int),
+    OutOfBounds(Pos, Int),
+    UndefinedElement(Pos, Int),
+    IncompatibleOperand(usize, Value),
+    InvalidAllocation(Int),
+    InvalidAddress(Value),
+    UndefinedAddress(Int),
+    UseAfterFree(Int),
+    DoubleFree(Int),
+    InvalidFree(Int),
+    FloatIndex(Pos, Value),
+    InvalidInput(String),
+    ReadFailed(String),
+}
+
+fn diagnostic(
+    f: &mut fmt::Formatter,
+    (title, color): (&str, Color),
+    message: &str,
+    file: Option<&str>,
+    line: &str,
+    span: Span,
+) -> fmt::Result {
+    let Span { start, end } = span;
+    let indent = line
+        .chars()
+        .take(start.column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let width = match end.line == start.line {
+        true => end.column.saturating_sub(start.column),
+        false => line.chars().count().saturating_sub(start.column),
+    };
+    let y = start.line;
+    write!(
+        f,
+        "\n{}: {}\n {: <digit$} {}\n{} {}\n {: <digit$} {} {}{} {}\n",
+        color!(title, color),
+        color!(message, Color::BrightWhite),
+        "",
+        match file {
+            Some(file) => format!(
+                "{} {}:{}:{}",
+                color!("-->", Color::BrightBlue),
+                file,
+                y + 1,
+                start.column + 1
+            ),
+            None => color!("|", Color::BrightBlue),
+        },
+        color!(format!(" {} |", y + 1), Color::BrightBlue),
+        line,
+        "",
+        color!("|", Color::BrightBlue),
+        indent,
+        color!("^".repeat(width.max(1)), color),
+        color!(message, color),
+        digit = (y + 1).to_string().len(),
+    )
 }
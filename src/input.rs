@@ -0,0 +1,88 @@
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+
+pub struct Input {
+    reader: Box<dyn BufRead>,
+    pub eof: bool,
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Self::new(BufReader::new(io::stdin()))
+    }
+}
+
+impl Input {
+    pub fn new(reader: impl BufRead + 'static) -> Self {
+        Self {
+            reader: Box::new(reader),
+            eof: false,
+        }
+    }
+    pub fn read_char(&mut self) -> io::Result<Option<char>> {
+        io::stdout().flush()?;
+        let first = match self.read_byte()? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        let width = match first {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 0,
+        };
+        let mut bytes = vec![first];
+        for _ in 1..width {
+            match self.peek_byte()? {
+                Some(byte) if byte & 0xc0 == 0x80 => {
+                    self.reader.consume(1);
+                    bytes.push(byte);
+                }
+                _ => break,
+            }
+        }
+        match std::str::from_utf8(&bytes) {
+            Ok(s) => Ok(s.chars().next()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )),
+        }
+    }
+    pub fn read_word(&mut self) -> io::Result<Option<String>> {
+        io::stdout().flush()?;
+        while let Some(byte) = self.peek_byte()? {
+            if !byte.is_ascii_whitespace() {
+                break;
+            }
+            self.reader.consume(1);
+        }
+        let mut bytes = Vec::new();
+        while let Some(byte) = self.peek_byte()? {
+            if byte.is_ascii_whitespace() {
+                break;
+            }
+            self.reader.consume(1);
+            bytes.push(byte);
+        }
+        if bytes.is_empty() {
+            self.eof = true;
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&bytes).to_string()))
+    }
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.peek_byte()?;
+        match byte {
+            Some(_) => self.reader.consume(1),
+            None => self.eof = true,
+        }
+        Ok(byte)
+    }
+    fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.reader.fill_buf()?.first().copied())
+    }
+}
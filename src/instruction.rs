@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::op;
+use crate::Label;
+use crate::Op;
+use crate::Pos;
+use crate::Ref;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Pin(Label, Vec<Pos>),
+    Arr(Pos, Ref),
+    New(Ref, Ref),
+    Del(Ref),
+    Loc(Pos),
+    Mov(Ref, Ref),
+    Add(Ref, Ref),
+    Sub(Ref, Ref),
+    Mul(Ref, Ref),
+    Div(Ref, Ref),
+    Mod(Ref, Ref),
+    And(Ref, Ref),
+    Or(Ref, Ref),
+    Xor(Ref, Ref),
+    Not(Ref),
+    Shl(Ref, Ref),
+    Shr(Ref, Ref),
+    Cmp(Ref, Ref),
+    Flt(Ref),
+    Trn(Ref),
+    Jif(Label),
+    Jel(Label),
+    Jlt(Label),
+    Jgt(Label),
+    Jle(Label),
+    Jge(Label),
+    Jmp(Label),
+    Cal(Label, Vec<Ref>, Option<Ref>),
+    Out(Ref),
+    Utf(Ref),
+    Prt(String),
+    Inp(Ref),
+    Get(Ref),
+    Eof(Ref),
+    Ret(Option<Ref>),
+    End,
+}
+
+impl Instruction {
+    pub fn name(&self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Pin(_, _) => "pin",
+            Arr(_, _) => "arr",
+            New(_, _) => "new",
+            Del(_) => "del",
+            Loc(_) => "loc",
+            Mov(_, _) => "mov",
+            Add(_, _) => "add",
+            Sub(_, _) => "sub",
+            Mul(_, _) => "mul",
+            Div(_, _) => "div",
+            Mod(_, _) => "mod",
+            And(_, _) => "and",
+            Or(_, _) => "or",
+            Xor(_, _) => "xor",
+            Not(_) => "not",
+            Shl(_, _) => "shl",
+            Shr(_, _) => "shr",
+            Cmp(_, _) => "cmp",
+            Flt(_) => "flt",
+            Trn(_) => "trn",
+            Jif(_) => "jif",
+            Jel(_) => "jel",
+            Jlt(_) => "jlt",
+            Jgt(_) => "jgt",
+            Jle(_) => "jle",
+            Jge(_) => "jge",
+            Jmp(_) => "jmp",
+            Cal(_, _, _) => "cal",
+            Out(_) => "out",
+            Utf(_) => "utf",
+            Prt(_) => "prt",
+            Inp(_) => "inp",
+            Get(_) => "get",
+            Eof(_) => "eof",
+            Ret(_) => "ret",
+            End => "end",
+        }
+    }
+    pub fn label(&self) -> Option<&Label> {
+        use Instruction::*;
+        match self {
+            Pin(label, _) | Cal(label, _, _) => Some(label),
+            Jif(label) | Jel(label) | Jlt(label) | Jgt(label) | Jle(label) | Jge(label)
+            | Jmp(label) => Some(label),
+            _ => None,
+        }
+    }
+    pub fn label_mut(&mut self) -> Option<&mut Label> {
+        use Instruction::*;
+        match self {
+            Pin(label, _) | Cal(label, _, _) => Some(label),
+            Jif(label) | Jel(label) | Jlt(label) | Jgt(label) | Jle(label) | Jge(label)
+            | Jmp(label) => Some(label),
+            _ => None,
+        }
+    }
+    pub fn reads(&self) -> Vec<(usize, &Pos)> {
+        use Instruction::*;
+        let mut reads = Vec::new();
+        match self {
+            Arr(_, size) => read(&mut reads, 2, size),
+            New(a, b) | Mov(a, b) => {
+                write(&mut reads, 1, a);
+                read(&mut reads, 2, b);
+            }
+            Add(a, b)
+            | Sub(a, b)
+            | Mul(a, b)
+            | Div(a, b)
+            | Mod(a, b)
+            | And(a, b)
+            | Or(a, b)
+            | Xor(a, b)
+            | Shl(a, b)
+            | Shr(a, b)
+            | Cmp(a, b) => {
+                read(&mut reads, 1, a);
+                read(&mut reads, 2, b);
+            }
+            Del(a) | Not(a) | Flt(a) | Trn(a) | Out(a) | Utf(a) => read(&mut reads, 1, a),
+            Inp(a) | Get(a) | Eof(a) => write(&mut reads, 1, a),
+            Cal(_, args, target) => {
+                for (i, arg) in args.iter().enumerate() {
+                    read(&mut reads, i + 2, arg);
+                }
+                if let Some(target) = target {
+                    write(&mut reads, args.len() + 3, target);
+                }
+            }
+            Ret(Some(value)) => read(&mut reads, 1, value),
+            _ => (),
+        }
+        reads
+    }
+    pub fn defines(&self) -> Vec<&Pos> {
+        use Instruction::*;
+        match self {
+            New(Ref::Pos(pos), _)
+            | Mov(Ref::Pos(pos), _)
+            | Add(Ref::Pos(pos), _)
+            | Sub(Ref::Pos(pos), _)
+            | Mul(Ref::Pos(pos), _)
+            | Div(Ref::Pos(pos), _)
+            | Mod(Ref::Pos(pos), _)
+            | And(Ref::Pos(pos), _)
+            | Or(Ref::Pos(pos), _)
+            | Xor(Ref::Pos(pos), _)
+            | Shl(Ref::Pos(pos), _)
+            | Shr(Ref::Pos(pos), _)
+            | Not(Ref::Pos(pos))
+            | Flt(Ref::Pos(pos))
+            | Trn(Ref::Pos(pos))
+            | Get(Ref::Pos(pos))
+            | Eof(Ref::Pos(pos)) => vec![pos],
+            _ => Vec::new(),
+        }
+    }
+    pub fn to_op(&self, pins: &HashMap<Label, (usize, Vec<Pos>)>) -> Box<dyn Op> {
+        use Instruction::*;
+        let target = |label: &Label| pins[label].0;
+        match self.clone() {
+            Pin(_, _) => Box::new(op::Pin),
+            Arr(pos, size) => Box::new(op::Arr(pos, size)),
+            New(pos, size) => Box::new(op::New(pos, size)),
+            Del(address) => Box::new(op::Del(address)),
+            Loc(pos) => Box::new(op::Loc(pos)),
+            Mov(a, b) => Box::new(op::Mov(a, b)),
+            Add(a, b) => Box::new(op::Add(a, b)),
+            Sub(a, b) => Box::new(op::Sub(a, b)),
+            Mul(a, b) => Box::new(op::Mul(a, b)),
+            Div(a, b) => Box::new(op::Div(a, b)),
+            Mod(a, b) => Box::new(op::Mod(a, b)),
+            And(a, b) => Box::new(op::And(a, b)),
+            Or(a, b) => Box::new(op::Or(a, b)),
+            Xor(a, b) => Box::new(op::Xor(a, b)),
+            Not(a) => Box::new(op::Not(a)),
+            Shl(a, b) => Box::new(op::Shl(a, b)),
+            Shr(a, b) => Box::new(op::Shr(a, b)),
+            Cmp(a, b) => Box::new(op::Cmp(a, b)),
+            Flt(a) => Box::new(op::Flt(a)),
+            Trn(a) => Box::new(op::Trn(a)),
+            Jif(label) => Box::new(op::Jif(target(&label))),
+            Jel(label) => Box::new(op::Jel(target(&label))),
+            Jlt(label) => Box::new(op::Jlt(target(&label))),
+            Jgt(label) => Box::new(op::Jgt(target(&label))),
+            Jle(label) => Box::new(op::Jle(target(&label))),
+            Jge(label) => Box::new(op::Jge(target(&label))),
+            Jmp(label) => Box::new(op::Jmp(target(&label))),
+            Cal(label, args, result) => {
+                let (index, params) = pins[&label].clone();
+                Box::new(op::Cal(index, params, args, result))
+            }
+            Out(a) => Box::new(op::Out(a)),
+            Utf(a) => Box::new(op::Utf(a)),
+            Prt(text) => Box::new(op::Prt(text)),
+            Inp(a) => Box::new(op::Inp(a)),
+            Get(a) => Box::new(op::Get(a)),
+            Eof(a) => Box::new(op::Eof(a)),
+            Ret(value) => Box::new(op::Ret(value)),
+            End => Box::new(op::End),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+        match self {
+            Pin(label, params) => {
+                write!(f, ":{}", label)?;
+                for param in params {
+                    write!(f, " {}", param)?;
+                }
+                Ok(())
+            }
+            Arr(pos, size) => write!(f, "arr {} {}", pos, size),
+            Loc(pos) => write!(f, "loc {}", pos),
+            New(a, b)
+            | Mov(a, b)
+            | Add(a, b)
+            | Sub(a, b)
+            | Mul(a, b)
+            | Div(a, b)
+            | Mod(a, b)
+            | And(a, b)
+            | Or(a, b)
+            | Xor(a, b)
+            | Shl(a, b)
+            | Shr(a, b)
+            | Cmp(a, b) => {
+                write!(f, "{} {} {}", self.name(), a, b)
+            }
+            Del(a) | Not(a) | Flt(a) | Trn(a) | Out(a) | Utf(a) | Inp(a) | Get(a) | Eof(a) => {
+                write!(f, "{} {}", self.name(), a)
+            }
+            Jif(label) | Jel(label) | Jlt(label) | Jgt(label) | Jle(label) | Jge(label)
+            | Jmp(label) => write!(f, "{} {}", self.name(), label),
+            Cal(label, args, target) => {
+                write!(f, "cal {}", label)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                match target {
+                    Some(target) => write!(f, " -> {}", target),
+                    None => Ok(()),
+                }
+            }
+            Prt(text) => {
+                write!(f, "prt \"")?;
+                for c in text.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\0' => write!(f, "\\0")?,
+                        '\\' | '"' => write!(f, "\\{}", c)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Ret(Some(value)) => write!(f, "ret {}", value),
+            Ret(None) | End => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+fn read<'a>(reads: &mut Vec<(usize, &'a Pos)>, arg: usize, refer: &'a Ref) {
+    match refer {
+        Ref::Pos(pos) => reads.push((arg, pos)),
+        Ref::Value(_) => (),
+        Ref::Index(_, index) => read(reads, arg, index),
+        Ref::Deref(pointer) => read(reads, arg, pointer),
+    }
+}
+
+fn write<'a>(reads: &mut Vec<(usize, &'a Pos)>, arg: usize, refer: &'a Ref) {
+    match refer {
+        Ref::Pos(_) | Ref::Value(_) => (),
+        Ref::Index(_, index) => read(reads, arg, index),
+        Ref::Deref(pointer) => read(reads, arg, pointer),
+    }
+}
@@ -0,0 +1,471 @@
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use crate::error::CompileErrorKind;
+use crate::parse_value;
+use crate::Location;
+use crate::Span;
+use crate::Token;
+use crate::TokenKind;
+use crate::Value;
+
+pub type LexResult<T> = Result<T, (CompileErrorKind, Span)>;
+
+pub struct Lexer<'a> {
+    raw: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    column: usize,
+    tokens: Vec<Token>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(raw: &'a str) -> Self {
+        Self {
+            raw,
+            chars: raw.char_indices().peekable(),
+            line: 0,
+            column: 0,
+            tokens: Vec::new(),
+        }
+    }
+    pub fn tokenize(mut self) -> (Vec<Token>, Vec<(CompileErrorKind, Span)>) {
+        let mut errors = Vec::new();
+        while let Some(c) = self.peek() {
+            let start = self.location();
+            if let Err(err) = self.token(c, start) {
+                errors.push(err);
+                self.recover();
+            }
+        }
+        let end = self.location();
+        self.push(TokenKind::Break, end);
+        (self.tokens, errors)
+    }
+    fn token(&mut self, c: char, start: Location) -> LexResult<()> {
+        match c {
+            '\n' => {
+                self.bump();
+                self.push(TokenKind::Break, start);
+            }
+            c if c.is_whitespace() => {
+                self.bump();
+            }
+            ':' => {
+                self.bump();
+                self.push(TokenKind::Pin, start);
+            }
+            '&' => self.cast(start)?,
+            '"' if is_text_operand(&self.tokens) => self.text(start)?,
+            '"' => self.comment(start)?,
+            '-' if self.peek_second() == Some('>') => {
+                self.bump();
+                self.bump();
+                self.push(TokenKind::Symbol("->".to_string()), start);
+            }
+            '-' if self.peek_second().is_some_and(|c| c.is_ascii_digit()) => self.value(start)?,
+            '=' | '!' | '<' | '>' => {
+                while self
+                    .bump_if(|c| matches!(c, '=' | '!' | '<' | '>'))
+                    .is_some()
+                {}
+                let operator = self.slice(start);
+                self.push(TokenKind::Symbol(operator), start);
+            }
+            c if c.is_ascii_digit() => self.value(start)?,
+            c if c.is_alphabetic() || c == '_' || c == '*' => self.symbol(start)?,
+            '\'' => {
+                self.bump();
+                return Err((CompileErrorKind::InvalidCast, self.span(start)));
+            }
+            c => {
+                self.bump();
+                return Err((CompileErrorKind::UnexpectedChar(c), self.span(start)));
+            }
+        }
+        Ok(())
+    }
+    fn recover(&mut self) {
+        let line = self
+            .tokens
+            .iter()
+            .rposition(|token| matches!(token.kind, TokenKind::Break))
+            .map_or(0, |index| index + 1);
+        self.tokens.truncate(line);
+        while self.bump_if(|c| c != '\n').is_some() {}
+    }
+    fn symbol(&mut self, start: Location) -> LexResult<()> {
+        while self.bump_if(|c| c == '*').is_some() {}
+        loop {
+            match self.peek() {
+                Some('[') => {
+                    self.bump();
+                    while self.bump_if(|c| c != ']' && !c.is_whitespace()).is_some() {}
+                    if self.bump_if(|c| c == ']').is_none() || !is_valid_index(&self.slice(start)) {
+                        return Err((CompileErrorKind::InvalidIndex, self.span(start)));
+                    }
+                }
+                Some(c) if c.is_alphanumeric() || c == '_' || c == '.' => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+        let symbol = self.slice(start);
+        self.push(TokenKind::Symbol(symbol), start);
+        Ok(())
+    }
+    fn value(&mut self, start: Location) -> LexResult<()> {
+        self.bump();
+        loop {
+            if self
+                .bump_if(|c| c.is_alphanumeric() || c == '_' || c == '.')
+                .is_some()
+            {
+                continue;
+            }
+            if !is_exponent(&self.slice(start)) || self.bump_if(|c| c == '+' || c == '-').is_none()
+            {
+                break;
+            }
+        }
+        let literal = self.slice(start);
+        match parse_value(&literal) {
+            Err(CompileErrorKind::InvalidDigit(c, radix)) => {
+                let span = digit_span(&literal, c, start);
+                return Err((CompileErrorKind::InvalidDigit(c, radix), span));
+            }
+            Err(kind) => return Err((kind, self.span(start))),
+            Ok(_) => (),
+        }
+        self.push(TokenKind::Value(literal), start);
+        Ok(())
+    }
+    fn cast(&mut self, start: Location) -> LexResult<()> {
+        self.bump();
+        if self.bump_if(|c| c == '\'').is_none() {
+            return Err((CompileErrorKind::InvalidCast, self.span(start)));
+        }
+        let mut cast = String::new();
+        loop {
+            let escape_start = self.location();
+            match self.peek() {
+                None | Some('\r' | '\n') => {
+                    return Err((CompileErrorKind::InvalidBlock, self.span(start)));
+                }
+                Some('\'') => {
+                    self.bump();
+                    break;
+                }
+                Some('\\') => {
+                    self.bump();
+                    match self.escape(true) {
+                        Some(c) => cast.push(c),
+                        None => {
+                            return Err((CompileErrorKind::InvalidEscape, self.span(escape_start)))
+                        }
+                    }
+                }
+                Some(c) => {
+                    self.bump();
+                    cast.push(c);
+                }
+            }
+        }
+        self.push(TokenKind::Cast(cast), start);
+        Ok(())
+    }
+    fn text(&mut self, start: Location) -> LexResult<()> {
+        self.bump();
+        let mut text = String::new();
+        loop {
+            let escape_start = self.location();
+            match self.peek() {
+                None | Some('\r' | '\n') => {
+                    return Err((CompileErrorKind::UnterminatedText, self.span(start)));
+                }
+                Some('"') => {
+                    self.bump();
+                    break;
+                }
+                Some('\\') => {
+                    self.bump();
+                    match self.escape(false) {
+                        Some(c) => text.push(c),
+                        None => {
+                            return Err((CompileErrorKind::InvalidEscape, self.span(escape_start)))
+                        }
+                    }
+                }
+                Some(c) => {
+                    self.bump();
+                    text.push(c);
+                }
+            }
+        }
+        self.push(TokenKind::Text(text), start);
+        Ok(())
+    }
+    fn comment(&mut self, start: Location) -> LexResult<()> {
+        if !self.raw[start.offset..].starts_with("\"\"\"") {
+            while self.bump_if(|c| c != '\n').is_some() {}
+            return Ok(());
+        }
+        for _ in 0..3 {
+            self.bump();
+        }
+        let opening = self.span(start);
+        let mut quotes = 0;
+        let mut newline = None;
+        while quotes < 3 {
+            if newline.is_none() && self.peek() == Some('\n') {
+                newline = Some(self.location());
+            }
+            match self.bump() {
+                Some('"') => quotes += 1,
+                Some(_) => quotes = 0,
+                None => return Err((CompileErrorKind::UnterminatedComment, opening)),
+            }
+        }
+        if let Some(newline) = newline {
+            self.push(TokenKind::Break, newline);
+        }
+        Ok(())
+    }
+    fn escape(&mut self, unicode: bool) -> Option<char> {
+        match self.bump()? {
+            'u' if unicode => {
+                self.bump_if(|c| c == '{')?;
+                let start = self.location();
+                while self.bump_if(|c| c.is_ascii_hexdigit()).is_some() {}
+                let digits = self.slice(start);
+                self.bump_if(|c| c == '}')?;
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+            }
+            c => escape(c),
+        }
+    }
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+    fn peek_second(&self) -> Option<char> {
+        self.chars.clone().nth(1).map(|(_, c)| c)
+    }
+    fn bump(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+    fn bump_if(&mut self, accept: impl Fn(char) -> bool) -> Option<char> {
+        match self.peek() {
+            Some(c) if accept(c) => self.bump(),
+            _ => None,
+        }
+    }
+    fn location(&mut self) -> Location {
+        Location {
+            offset: self
+                .chars
+                .peek()
+                .map_or(self.raw.len(), |(offset, _)| *offset),
+            line: self.line,
+            column: self.column,
+        }
+    }
+    fn span(&mut self, start: Location) -> Span {
+        Span {
+            start,
+            end: self.location(),
+        }
+    }
+    fn slice(&mut self, start: Location) -> String {
+        let end = self.location();
+        self.raw[start.offset..end.offset].to_string()
+    }
+    fn push(&mut self, kind: TokenKind, start: Location) {
+        let span = self.span(start);
+        self.tokens.push(Token {
+            kind,
+            span,
+            expansion: None,
+        });
+    }
+}
+
+fn is_valid_index(symbol: &str) -> bool {
+    let index = match symbol.strip_suffix(']').and_then(|s| s.split_once('[')) {
+        Some((_, index)) => index,
+        None => return false,
+    };
+    if matches!(parse_value(index), Ok(Value::Int(_))) {
+        return true;
+    }
+    let mut chars = index.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn digit_span(literal: &str, digit: char, start: Location) -> Span {
+    let prefix = literal.trim_start_matches('-');
+    let skip = literal.len() - prefix.len()
+        + match prefix.get(..2) {
+            Some("0x" | "0o" | "0b") => 2,
+            _ => 0,
+        };
+    let offset = literal[skip..]
+        .find(digit)
+        .map_or(0, |offset| skip + offset);
+    let start = Location {
+        offset: start.offset + offset,
+        line: start.line,
+        column: start.column + literal[..offset].chars().count(),
+    };
+    let end = Location {
+        offset: start.offset + digit.len_utf8(),
+        line: start.line,
+        column: start.column + 1,
+    };
+    Span { start, end }
+}
+
+fn is_exponent(literal: &str) -> bool {
+    let digits = literal.trim_start_matches('-');
+    digits.ends_with(['e', 'E']) && !matches!(digits.get(..2), Some("0x" | "0o" | "0b"))
+}
+
+fn is_text_operand(tokens: &[Token]) -> bool {
+    let mut line = tokens
+        .iter()
+        .rev()
+        .take_while(|token| !matches!(token.kind, TokenKind::Break));
+    matches!(line.next(), Some(Token { kind: TokenKind::Symbol(name), .. }) if name == "prt" || name == "inc")
+        && line.next().is_none()
+}
+
+fn escape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '"' | '\'' => Some(c),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(raw: &str) -> Vec<String> {
+        let (tokens, errors) = Lexer::new(raw).tokenize();
+        assert!(errors.is_empty(), "{:?}", errors);
+        tokens
+            .iter()
+            .map(|token| format!("{:?}", token.kind))
+            .collect()
+    }
+
+    #[test]
+    fn tokenizes_operations() {
+        assert_eq!(
+            kinds(":loop\nmov a[0] -1 \"comment\"\nutf &'\\n' -> b"),
+            [
+                "Pin",
+                "Symbol(\"loop\")",
+                "Break",
+                "Symbol(\"mov\")",
+                "Symbol(\"a[0]\")",
+                "Value(\"-1\")",
+                "Break",
+                "Symbol(\"utf\")",
+                "Cast(\"\\n\")",
+                "Symbol(\"->\")",
+                "Symbol(\"b\")",
+                "Break",
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_spans() {
+        let (tokens, _) = Lexer::new("mov a 1\n  out a").tokenize();
+        let out = &tokens[4];
+        assert_eq!(format!("{:?}", out.kind), "Symbol(\"out\")");
+        assert_eq!((out.span.start.offset, out.span.end.offset), (10, 13));
+        assert_eq!((out.span.start.line, out.span.start.column), (1, 2));
+    }
+
+    #[test]
+    fn lexes_signed_exponents() {
+        assert_eq!(kinds("1e-7")[0], "Value(\"1e-7\")");
+        assert_eq!(kinds("2.5E+3")[0], "Value(\"2.5E+3\")");
+        assert_eq!(
+            kinds("0x1e-1")[..3],
+            ["Value(\"0x1e\")", "Value(\"-1\")", "Break"]
+        );
+    }
+
+    #[test]
+    fn ends_line_at_multiline_comment() {
+        assert_eq!(
+            kinds("mov a 1 \"\"\"c\n\"\"\"out a"),
+            [
+                "Symbol(\"mov\")",
+                "Symbol(\"a\")",
+                "Value(\"1\")",
+                "Break",
+                "Symbol(\"out\")",
+                "Symbol(\"a\")",
+                "Break",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_misplaced_separators() {
+        for literal in ["1__2_", "1_", "0x_1", "1_.5", "1e_5"] {
+            let (_, errors) = Lexer::new(literal).tokenize();
+            assert!(
+                matches!(errors[..], [(CompileErrorKind::InvalidSeparator(_), _)]),
+                "{}",
+                literal
+            );
+        }
+        assert_eq!(
+            kinds("1_000 0xdead_beef")[..2],
+            ["Value(\"1_000\")", "Value(\"0xdead_beef\")"]
+        );
+    }
+
+    #[test]
+    fn points_at_invalid_digit() {
+        let (_, errors) = Lexer::new("out -0x1G").tokenize();
+        let [(CompileErrorKind::InvalidDigit('G', 16), span)] = errors[..] else {
+            panic!("{:?}", errors);
+        };
+        assert_eq!((span.start.column, span.end.column), (8, 9));
+    }
+
+    #[test]
+    fn recovers_at_next_line() {
+        let (tokens, errors) = Lexer::new("mov a $\nout a").tokenize();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].0, CompileErrorKind::UnexpectedChar('$')));
+        let kinds: Vec<_> = tokens
+            .iter()
+            .map(|token| format!("{:?}", token.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            ["Break", "Symbol(\"out\")", "Symbol(\"a\")", "Break"]
+        );
+    }
+}
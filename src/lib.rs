@@ -1,18 +1,31 @@
 mod color;
 mod compiler;
+mod input;
+mod instruction;
+mod lexer;
 mod runtime;
 
 pub mod error;
 pub mod op;
 
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use error::CompileError;
+use error::CompileErrorKind;
+use error::CompileWarning;
 use error::PerformError;
 use op::OpWrap;
 
 pub use color::Color;
 pub use compiler::Compiler;
+pub use input::Input;
+pub use instruction::Instruction;
+pub use runtime::Frame;
+pub use runtime::OverflowMode;
 pub use runtime::Runtime;
 
 #[macro_export]
@@ -27,7 +40,8 @@ macro_rules! unwrap_or_throw {
 
 pub type Label = String;
 pub type Pos = String;
-pub type Value = usize;
+pub type Int = i64;
+pub type Float = f64;
 
 pub type PerformResult = Result<(), PerformError>;
 
@@ -35,41 +49,268 @@ pub trait Op {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult;
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(Int),
+    Float(Float),
+}
+
+impl Value {
+    pub fn as_float(self) -> Float {
+        match self {
+            Value::Int(value) => value as Float,
+            Value::Float(value) => value,
+        }
+    }
+    pub fn is_zero(self) -> bool {
+        self.as_float() == 0.0
+    }
+    pub fn compare(self, other: Value) -> Ordering {
+        match (self, other) {
+            (Value::Int(value), Value::Int(other)) => value.cmp(&other),
+            (value, other) => {
+                let (value, other) = (value.as_float(), other.as_float());
+                value
+                    .partial_cmp(&other)
+                    .unwrap_or_else(|| value.total_cmp(&other))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Ref {
     Pos(Pos),
-    Value(usize),
+    Value(Value),
+    Index(Pos, Box<Ref>),
+    Deref(Box<Ref>),
+}
+
+impl fmt::Display for Ref {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ref::Pos(pos) => write!(f, "{}", pos),
+            Ref::Value(value) => write!(f, "{}", value),
+            Ref::Index(pos, index) => write!(f, "{}[{}]", pos, index),
+            Ref::Deref(pointer) => write!(f, "*{}", pointer),
+        }
+    }
 }
 
 impl FromStr for Ref {
     type Err = CompileError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let value = s.parse::<Value>();
+        let value = parse_value(s);
         if let Ok(value) = value {
             return Ok(Ref::Value(value));
         }
+        if let Some(pointer) = s.strip_prefix('*') {
+            return Ok(Ref::Deref(Box::new(Ref::from_str(pointer)?)));
+        }
+        if let Some((pos, index)) = s.strip_suffix(']').and_then(|s| s.split_once('[')) {
+            return Ok(Ref::Index(pos.to_string(), Box::new(Ref::from_str(index)?)));
+        }
         Ok(Ref::Pos(s.to_string()))
     }
 }
 
+pub fn parse_value(s: &str) -> Result<Value, CompileErrorKind> {
+    let (negative, literal) = match s.strip_prefix('-') {
+        Some(literal) => (true, literal),
+        None => (false, s),
+    };
+    let (radix, digits) = match literal.get(..2) {
+        Some("0x") => (16, &literal[2..]),
+        Some("0o") => (8, &literal[2..]),
+        Some("0b") => (2, &literal[2..]),
+        _ => (10, literal),
+    };
+    let groups = match radix {
+        10 => digits.split(['.', 'e', 'E', '+', '-']).collect(),
+        _ => vec![digits],
+    };
+    if groups
+        .iter()
+        .any(|group| group.starts_with('_') || group.ends_with('_') || group.contains("__"))
+    {
+        return Err(CompileErrorKind::InvalidSeparator(s.to_string()));
+    }
+    if !digits.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(CompileErrorKind::InvalidLiteral(s.to_string()));
+    }
+    if radix == 10 && digits.contains(['.', 'e', 'E']) {
+        return parse_float(s);
+    }
+    let mut value: Int = 0;
+    for c in digits.chars().filter(|c| *c != '_') {
+        let digit = match c.to_digit(radix) {
+            Some(digit) => digit as Int,
+            None => return Err(CompileErrorKind::InvalidDigit(c, radix)),
+        };
+        let result = value
+            .checked_mul(radix as Int)
+            .and_then(|value| match negative {
+                true => value.checked_sub(digit),
+                false => value.checked_add(digit),
+            });
+        value = match result {
+            Some(value) => value,
+            None => return Err(CompileErrorKind::LiteralOverflow(s.to_string())),
+        };
+    }
+    Ok(Value::Int(value))
+}
+
+fn parse_float(s: &str) -> Result<Value, CompileErrorKind> {
+    let literal = s.replace('_', "");
+    let mantissa = literal.split(['e', 'E']).next().unwrap_or_default();
+    if let Some((whole, fraction)) = mantissa.trim_start_matches('-').split_once('.') {
+        if whole.is_empty() || fraction.is_empty() {
+            return Err(CompileErrorKind::InvalidLiteral(s.to_string()));
+        }
+    }
+    match literal.parse::<Float>() {
+        Ok(value) if value.is_finite() => Ok(Value::Float(value)),
+        Ok(_) => Err(CompileErrorKind::LiteralOverflow(s.to_string())),
+        Err(_) => Err(CompileErrorKind::InvalidLiteral(s.to_string())),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum TokenKind {
     Symbol(String),
     Value(String),
     Break,
     Pin,
-    Comment,
     Cast(String),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Location {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: Location,
+    pub end: Location,
 }
 
 #[derive(Debug, Clone)]
 pub struct Token {
     pub kind: TokenKind,
-    pub pos: (usize, usize),
+    pub span: Span,
+    pub expansion: Option<usize>,
+}
+
+pub struct Source {
+    pub path: Option<PathBuf>,
+    pub raw: String,
+}
+
+impl Source {
+    pub fn file(&self) -> Option<String> {
+        self.path.as_ref().map(|path| path.display().to_string())
+    }
+    pub fn line(&self, span: Span) -> String {
+        self.raw
+            .lines()
+            .nth(span.start.line)
+            .unwrap_or("")
+            .to_string()
+    }
 }
 
 pub struct Executable {
     pub ops: Vec<OpWrap>,
-    pub raw: String,
+    pub sources: Vec<Source>,
+    pub warnings: Vec<CompileWarning>,
+}
+
+impl Executable {
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.ops.iter().map(|opwrap| &opwrap.instruction)
+    }
+}
+
+impl fmt::Display for Executable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = self
+            .instructions()
+            .filter_map(Instruction::label)
+            .collect::<Vec<_>>();
+        let mut names = HashMap::new();
+        for label in labels.iter().filter(|label| label.contains('#')) {
+            if names.contains_key(*label) {
+                continue;
+            }
+            let mut name = label.replace('#', "_");
+            while labels.contains(&&name) || names.values().any(|other| *other == name) {
+                name.push('_');
+            }
+            names.insert(label.to_string(), name);
+        }
+        for instruction in self.instructions() {
+            let mut instruction = instruction.clone();
+            if let Some(label) = instruction.label_mut() {
+                if let Some(name) = names.get(label) {
+                    *label = name.clone();
+                }
+            }
+            writeln!(f, "{}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(raw: &str) -> String {
+        let first = Compiler::default().compile(raw).unwrap().to_string();
+        let second = Compiler::default().compile(&first).unwrap().to_string();
+        assert_eq!(first, second);
+        first
+    }
+
+    #[test]
+    fn round_trips_small_floats() {
+        let printed = round_trip("mov a 0.0000001\nmov b 1e-7\nout a\nout b");
+        assert!(printed.contains("1e-7"));
+    }
+
+    #[test]
+    fn round_trips_blocks_and_generated_labels() {
+        round_trip(
+            "mov n 0\nwhile n < 3\n  if n == 1\n    prt \"one\\n\"\n  else\n    out n\n  fi\n  add n 1\ndone\n:_while0\nend",
+        );
+    }
+
+    #[test]
+    fn round_trips_macros_records_and_calls() {
+        round_trip(
+            "def show c\n  out c\nenddef\nrec point x y\nvar p point\nmov p.x 3\nshow p.x\ncal square 4 -> result\nshow result\nend\n:square n\n  mul n n\n  ret n",
+        );
+    }
+
+    #[test]
+    fn round_trips_examples() {
+        round_trip(include_str!("../examples/abc.ask"));
+        round_trip(include_str!("../examples/grid.ask"));
+        round_trip(include_str!("../examples/hello.ask"));
+    }
 }
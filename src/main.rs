@@ -5,12 +5,27 @@ use std::process;
 use ask::color;
 use ask::Color;
 use ask::Compiler;
+use ask::OverflowMode;
 use ask::Runtime;
 
 fn main() {
     let mut args = env::args();
     args.next();
-    let path = args.next();
+    let mut path = None;
+    let mut overflow = OverflowMode::default();
+    for arg in args {
+        if let Some(mode) = arg.strip_prefix("--overflow=") {
+            match mode.parse() {
+                Ok(mode) => overflow = mode,
+                Err(err) => {
+                    println!("{}", color!(err, Color::BrightRed));
+                    process::exit(1);
+                }
+            }
+            continue;
+        }
+        path = Some(arg);
+    }
     if path.is_none() {
         println!(
             "{}",
@@ -18,7 +33,8 @@ fn main() {
         );
         process::exit(1);
     }
-    let raw = fs::read_to_string(path.unwrap());
+    let path = path.unwrap();
+    let raw = fs::read_to_string(&path);
     if let Err(err) = raw {
         println!(
             "{}",
@@ -29,14 +45,21 @@ fn main() {
         );
         process::exit(1);
     }
-    let mut compiler = Compiler::default();
+    let mut compiler = Compiler::with_path(&path);
     let exe = compiler.compile(&raw.unwrap());
     if let Err(err) = &exe {
         println!("{}", err);
         process::exit(1);
     }
-    let mut runtime = Runtime::default();
-    if let Err(err) = runtime.execute(exe.unwrap()) {
+    let exe = exe.unwrap();
+    for warning in &exe.warnings {
+        println!("{}", warning);
+    }
+    let mut runtime = Runtime {
+        overflow,
+        ..Default::default()
+    };
+    if let Err(err) = runtime.execute(exe) {
         println!("{}", err);
     }
 }
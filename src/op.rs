@@ -1,159 +1,334 @@
+use std::collections::HashMap;
+
 use crate::error::PerformError;
 use crate::error::RuntimeErrorKind::*;
 use crate::unwrap_or_throw;
-use crate::Label;
+use crate::Float;
+use crate::Frame;
+use crate::Instruction;
+use crate::Int;
 use crate::Op;
 use crate::PerformResult;
 use crate::Pos;
 use crate::Ref;
 use crate::Runtime;
 use crate::Token;
+use crate::Value;
 
 pub struct OpWrap {
     pub op: Box<dyn Op>,
+    pub instruction: Instruction,
     pub tokens: Vec<Token>,
-    pub pre_init: bool,
+    pub source: usize,
 }
 
-pub struct Pin(pub Label);
-pub struct Mov(pub Pos, pub Ref);
-pub struct Add(pub Pos, pub Ref);
-pub struct Sub(pub Pos, pub Ref);
-pub struct Cmp(pub Pos, pub Ref);
-pub struct Jif(pub Label);
-pub struct Jel(pub Label);
-pub struct Jmp(pub Label);
+pub struct Pin;
+pub struct Arr(pub Pos, pub Ref);
+pub struct New(pub Ref, pub Ref);
+pub struct Del(pub Ref);
+pub struct Loc(pub Pos);
+pub struct Mov(pub Ref, pub Ref);
+pub struct Add(pub Ref, pub Ref);
+pub struct Sub(pub Ref, pub Ref);
+pub struct Mul(pub Ref, pub Ref);
+pub struct Div(pub Ref, pub Ref);
+pub struct Mod(pub Ref, pub Ref);
+pub struct And(pub Ref, pub Ref);
+pub struct Or(pub Ref, pub Ref);
+pub struct Xor(pub Ref, pub Ref);
+pub struct Not(pub Ref);
+pub struct Shl(pub Ref, pub Ref);
+pub struct Shr(pub Ref, pub Ref);
+pub struct Cmp(pub Ref, pub Ref);
+pub struct Flt(pub Ref);
+pub struct Trn(pub Ref);
+pub struct Jif(pub usize);
+pub struct Jel(pub usize);
+pub struct Jlt(pub usize);
+pub struct Jgt(pub usize);
+pub struct Jle(pub usize);
+pub struct Jge(pub usize);
+pub struct Jmp(pub usize);
+pub struct Cal(pub usize, pub Vec<Pos>, pub Vec<Ref>, pub Option<Ref>);
 pub struct Out(pub Ref);
 pub struct Utf(pub Ref);
-pub struct Ret;
+pub struct Prt(pub String);
+pub struct Inp(pub Ref);
+pub struct Get(pub Ref);
+pub struct Eof(pub Ref);
+pub struct Ret(pub Option<Ref>);
 pub struct End;
 
 impl Op for Pin {
+    fn perform(&self, _runtime: &mut Runtime) -> PerformResult {
+        Ok(())
+    }
+}
+
+impl Op for Arr {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let label = &self.0;
-        if runtime.pins.get(label).is_some() {
-            return Err(PerformError(DuplicatePin(label.clone())));
-        }
-        runtime.pins.insert(label.clone(), runtime.index);
+        let size = runtime.resolve_int(&self.1, 2)?;
+        let mut array = Vec::new();
+        let cells = usize::try_from(size)
+            .ok()
+            .filter(|cells| array.try_reserve_exact(*cells).is_ok());
+        let cells = unwrap_or_throw!(cells, PerformError(InvalidSize(size)));
+        array.resize(cells, None);
+        runtime.arrays.insert(self.0.clone(), array);
+        Ok(())
+    }
+}
+
+impl Op for New {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let size = runtime.resolve_int(&self.1, 2)?;
+        let size = unwrap_or_throw!(
+            usize::try_from(size).ok().filter(|size| *size > 0),
+            PerformError(InvalidAllocation(size))
+        );
+        let address = runtime.allocate(size)?;
+        runtime.set(&self.0, Value::Int(address))
+    }
+}
+
+impl Op for Del {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let address = runtime.resolve_address(&self.0)?;
+        runtime.free(address)
+    }
+}
+
+impl Op for Loc {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let frame = unwrap_or_throw!(runtime.frames.last_mut(), PerformError(NoFrame));
+        frame.locals.insert(self.0.clone(), None);
         Ok(())
     }
 }
 
 impl Op for Mov {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let pos = self.0.clone();
-        let refer = &self.1;
-        match refer {
-            Ref::Pos(pos_old) => {
-                let value =
-                    unwrap_or_throw!(runtime.memory.get(pos_old), PerformError(Undefined(pos)));
-                runtime.memory.insert(pos, *value);
-            }
-            Ref::Value(value) => {
-                runtime.memory.insert(pos, *value);
-            }
+        let value = runtime.resolve(&self.1)?;
+        runtime.set(&self.0, value)
+    }
+}
+
+impl Op for Add {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve(&self.0)?;
+        let other = runtime.resolve(&self.1)?;
+        let result = match (value, other) {
+            (Value::Int(value), Value::Int(other)) => Value::Int(runtime.arithmetic(
+                value.checked_add(other),
+                value.wrapping_add(other),
+                value.saturating_add(other),
+            )?),
+            (value, other) => Value::Float(value.as_float() + other.as_float()),
+        };
+        runtime.set(&self.0, result)
+    }
+}
+
+impl Op for Sub {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve(&self.0)?;
+        let other = runtime.resolve(&self.1)?;
+        let result = match (value, other) {
+            (Value::Int(value), Value::Int(other)) => Value::Int(runtime.arithmetic(
+                value.checked_sub(other),
+                value.wrapping_sub(other),
+                value.saturating_sub(other),
+            )?),
+            (value, other) => Value::Float(value.as_float() - other.as_float()),
+        };
+        runtime.set(&self.0, result)
+    }
+}
+
+impl Op for Mul {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve(&self.0)?;
+        let other = runtime.resolve(&self.1)?;
+        let result = match (value, other) {
+            (Value::Int(value), Value::Int(other)) => Value::Int(runtime.arithmetic(
+                value.checked_mul(other),
+                value.wrapping_mul(other),
+                value.saturating_mul(other),
+            )?),
+            (value, other) => Value::Float(value.as_float() * other.as_float()),
+        };
+        runtime.set(&self.0, result)
+    }
+}
+
+impl Op for Div {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve(&self.0)?;
+        let other = runtime.resolve(&self.1)?;
+        if other.is_zero() {
+            return Err(PerformError(DivisionByZero));
+        }
+        let result = match (value, other) {
+            (Value::Int(value), Value::Int(other)) => Value::Int(runtime.arithmetic(
+                value.checked_div(other),
+                value.wrapping_div(other),
+                value.saturating_div(other),
+            )?),
+            (value, other) => Value::Float(value.as_float() / other.as_float()),
+        };
+        runtime.set(&self.0, result)
+    }
+}
+
+impl Op for Mod {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve(&self.0)?;
+        let other = runtime.resolve(&self.1)?;
+        if other.is_zero() {
+            return Err(PerformError(DivisionByZero));
+        }
+        let result = match (value, other) {
+            (Value::Int(value), Value::Int(other)) => Value::Int(value.wrapping_rem(other)),
+            (value, other) => Value::Float(value.as_float() % other.as_float()),
+        };
+        runtime.set(&self.0, result)
+    }
+}
+
+impl Op for And {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve_int(&self.0, 1)?;
+        let other = runtime.resolve_int(&self.1, 2)?;
+        runtime.set(&self.0, Value::Int(value & other))
+    }
+}
+
+impl Op for Or {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve_int(&self.0, 1)?;
+        let other = runtime.resolve_int(&self.1, 2)?;
+        runtime.set(&self.0, Value::Int(value | other))
+    }
+}
+
+impl Op for Xor {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve_int(&self.0, 1)?;
+        let other = runtime.resolve_int(&self.1, 2)?;
+        runtime.set(&self.0, Value::Int(value ^ other))
+    }
+}
+
+impl Op for Not {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve_int(&self.0, 1)?;
+        runtime.set(&self.0, Value::Int(!value))
+    }
+}
+
+impl Op for Shl {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve_int(&self.0, 1)?;
+        let other = runtime.resolve_int(&self.1, 2)?;
+        if !(0..Int::BITS as Int).contains(&other) {
+            return Err(PerformError(InvalidShift(other)));
         }
+        runtime.set(&self.0, Value::Int(value << other))
+    }
+}
+
+impl Op for Shr {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve_int(&self.0, 1)?;
+        let other = runtime.resolve_int(&self.1, 2)?;
+        if !(0..Int::BITS as Int).contains(&other) {
+            return Err(PerformError(InvalidShift(other)));
+        }
+        runtime.set(&self.0, Value::Int(value >> other))
+    }
+}
+
+impl Op for Cmp {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve(&self.0)?;
+        let other = runtime.resolve(&self.1)?;
+        runtime.flag = Some(value.compare(other));
         Ok(())
     }
 }
 
-impl Op for Add {
+impl Op for Flt {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let pos = &self.0;
-        let value = unwrap_or_throw!(
-            runtime.memory.get(pos),
-            PerformError(Undefined(pos.clone()))
-        );
-        let refer_a = &self.1;
-        match refer_a {
-            Ref::Pos(pos_old) => {
-                let value_old = unwrap_or_throw!(
-                    runtime.memory.get(pos_old),
-                    PerformError(Undefined(pos_old.clone()))
-                );
-                runtime.memory.insert(pos.clone(), *value + value_old);
+        let value = runtime.resolve(&self.0)?;
+        runtime.set(&self.0, Value::Float(value.as_float()))
+    }
+}
+
+impl Op for Trn {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = match runtime.resolve(&self.0)? {
+            Value::Float(value) if value.is_nan() || value >= Int::MAX as Float => {
+                return Err(PerformError(Overflow))
             }
-            Ref::Value(value_old) => {
-                runtime.memory.insert(pos.clone(), value + *value_old);
+            Value::Float(value) if value < Int::MIN as Float => {
+                return Err(PerformError(Underflow))
             }
+            Value::Float(value) => value as Int,
+            Value::Int(value) => value,
+        };
+        runtime.set(&self.0, Value::Int(value))
+    }
+}
+
+impl Op for Jif {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        if runtime.compare()?.is_eq() {
+            runtime.jump(self.0);
         }
         Ok(())
     }
 }
 
-impl Op for Sub {
+impl Op for Jel {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let pos = &self.0;
-        let value = unwrap_or_throw!(
-            runtime.memory.get(pos),
-            PerformError(Undefined(pos.clone()))
-        );
-        let refer_a = &self.1;
-        match refer_a {
-            Ref::Pos(pos_old) => {
-                let value_old = unwrap_or_throw!(
-                    runtime.memory.get(pos_old),
-                    PerformError(Undefined(pos_old.clone()))
-                );
-                runtime.memory.insert(pos.clone(), *value - value_old);
-            }
-            Ref::Value(value_old) => {
-                runtime.memory.insert(pos.clone(), value - *value_old);
-            }
+        if runtime.compare()?.is_ne() {
+            runtime.jump(self.0);
         }
         Ok(())
     }
 }
 
-impl Op for Cmp {
+impl Op for Jlt {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let pos = &self.0;
-        let value = unwrap_or_throw!(
-            runtime.memory.get(pos),
-            PerformError(Undefined(pos.clone()))
-        );
-        let refer_a = &self.1;
-        match refer_a {
-            Ref::Pos(pos_old) => {
-                let value_old = unwrap_or_throw!(
-                    runtime.memory.get(pos_old),
-                    PerformError(Undefined(pos_old.clone()))
-                );
-                runtime
-                    .memory
-                    .insert("#".to_string(), if *value == *value_old { 1 } else { 0 });
-            }
-            Ref::Value(value_old) => {
-                runtime
-                    .memory
-                    .insert("#".to_string(), if *value == *value_old { 1 } else { 0 });
-            }
+        if runtime.compare()?.is_lt() {
+            runtime.jump(self.0);
         }
         Ok(())
     }
 }
 
-impl Op for Jif {
+impl Op for Jgt {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let code = unwrap_or_throw!(runtime.memory.get("#"), PerformError(NoCompare));
-        if *code == 1 {
-            let label = &self.0;
-            let pos = unwrap_or_throw!(runtime.pins.get(label), PerformError(NoPin(label.clone())));
-            runtime.stack.push(runtime.index);
-            runtime.index = *pos;
+        if runtime.compare()?.is_gt() {
+            runtime.jump(self.0);
         }
         Ok(())
     }
 }
 
-impl Op for Jel {
+impl Op for Jle {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let code = unwrap_or_throw!(runtime.memory.get("#"), PerformError(NoCompare));
-        if *code == 0 {
-            let label = &self.0;
-            let pos = unwrap_or_throw!(runtime.pins.get(label), PerformError(NoPin(label.clone())));
-            runtime.stack.push(runtime.index);
-            runtime.index = *pos;
+        if runtime.compare()?.is_le() {
+            runtime.jump(self.0);
+        }
+        Ok(())
+    }
+}
+
+impl Op for Jge {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        if runtime.compare()?.is_ge() {
+            runtime.jump(self.0);
         }
         Ok(())
     }
@@ -161,57 +336,105 @@ impl Op for Jel {
 
 impl Op for Jmp {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let label = &self.0;
-        let pos = unwrap_or_throw!(runtime.pins.get(label), PerformError(NoPin(label.clone())));
-        runtime.stack.push(runtime.index);
-        runtime.index = *pos;
+        runtime.jump(self.0);
         Ok(())
     }
 }
 
-impl Op for Out {
+impl Op for Cal {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let refer = &self.0;
-        match refer {
-            Ref::Pos(pos) => {
-                let value = unwrap_or_throw!(
-                    runtime.memory.get(pos),
-                    PerformError(Undefined(pos.clone()))
-                );
-                print!("{}", value);
-            }
-            Ref::Value(value) => {
-                print!("{}", value);
-            }
+        let mut locals = HashMap::new();
+        for (param, arg) in self.1.iter().zip(&self.2) {
+            locals.insert(param.clone(), Some(runtime.resolve(arg)?));
         }
+        runtime.frames.push(Frame {
+            index: runtime.index,
+            locals,
+            target: self.3.clone(),
+        });
+        runtime.jump(self.0);
+        Ok(())
+    }
+}
+
+impl Op for Out {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let value = runtime.resolve(&self.0)?;
+        print!("{}", value);
         Ok(())
     }
 }
 
 impl Op for Utf {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let refer = &self.0;
-        match refer {
-            Ref::Pos(pos) => {
-                let value = unwrap_or_throw!(
-                    runtime.memory.get(pos),
-                    PerformError(Undefined(pos.clone()))
-                );
-                print!("{}", String::from_utf8_lossy(&[*value as u8]));
-            }
-            Ref::Value(value) => {
-                print!("{}", String::from_utf8_lossy(&[*value as u8]));
-            }
+        let value = runtime.resolve_int(&self.0, 1)?;
+        let c = u32::try_from(value).ok().and_then(char::from_u32);
+        print!("{}", c.unwrap_or(char::REPLACEMENT_CHARACTER));
+        Ok(())
+    }
+}
+
+impl Op for Prt {
+    fn perform(&self, _runtime: &mut Runtime) -> PerformResult {
+        print!("{}", self.0);
+        Ok(())
+    }
+}
+
+impl Op for Inp {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let word = runtime
+            .input
+            .read_word()
+            .map_err(|err| PerformError(ReadFailed(err.to_string())))?;
+        if let Some(word) = word {
+            let value = match word.parse::<Int>() {
+                Ok(value) => Some(Value::Int(value)),
+                Err(_) => word
+                    .parse::<Float>()
+                    .ok()
+                    .filter(|value| value.is_finite())
+                    .map(Value::Float),
+            };
+            let value = unwrap_or_throw!(value, PerformError(InvalidInput(word)));
+            runtime.set(&self.0, value)?;
         }
         Ok(())
     }
 }
 
+impl Op for Get {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let c = runtime
+            .input
+            .read_char()
+            .map_err(|err| PerformError(ReadFailed(err.to_string())))?;
+        runtime.set(&self.0, Value::Int(c.map_or(-1, |c| c as Int)))
+    }
+}
+
+impl Op for Eof {
+    fn perform(&self, runtime: &mut Runtime) -> PerformResult {
+        let eof = runtime.input.eof;
+        runtime.set(&self.0, Value::Int(eof as Int))
+    }
+}
+
 impl Op for Ret {
     fn perform(&self, runtime: &mut Runtime) -> PerformResult {
-        let pos = unwrap_or_throw!(runtime.stack.pop(), PerformError(NoReturn));
-        runtime.index = pos;
-        Ok(())
+        let value = match &self.0 {
+            Some(refer) => Some(runtime.resolve(refer)?),
+            None => None,
+        };
+        let frame = unwrap_or_throw!(runtime.frames.pop(), PerformError(NoReturn));
+        runtime.index = frame.index;
+        match frame.target {
+            Some(target) => {
+                let value = unwrap_or_throw!(value, PerformError(NoReturnValue));
+                runtime.set(&target, value)
+            }
+            None => Ok(()),
+        }
     }
 }
 
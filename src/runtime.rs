@@ -1,65 +1,243 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::str::FromStr;
 
 use crate::error::PerformError;
 use crate::error::RuntimeError;
 use crate::error::RuntimeErrorKind::*;
 use crate::op::OpWrap;
+use crate::unwrap_or_throw;
 use crate::Executable;
-use crate::Label;
+use crate::Input;
+use crate::Int;
+use crate::PerformResult;
 use crate::Pos;
-use crate::TokenKind;
+use crate::Ref;
 use crate::Value;
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowMode {
+    #[default]
+    Trap,
+    Wrap,
+    Saturate,
+}
+
+impl FromStr for OverflowMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trap" => Ok(OverflowMode::Trap),
+            "wrap" => Ok(OverflowMode::Wrap),
+            "saturate" => Ok(OverflowMode::Saturate),
+            _ => Err(format!("Unknown overflow mode '{}'!", s)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Frame {
+    pub index: usize,
+    pub locals: HashMap<Pos, Option<Value>>,
+    pub target: Option<Ref>,
+}
+
+pub struct HeapCell {
+    pub block: Int,
+    pub value: Option<Value>,
+}
+
 #[derive(Default)]
 pub struct Runtime {
     pub memory: HashMap<Pos, Value>,
-    pub stack: Vec<usize>,
-    pub pins: HashMap<Label, usize>,
+    pub arrays: HashMap<Pos, Vec<Option<Value>>>,
+    pub heap: Vec<Option<HeapCell>>,
+    pub blocks: HashMap<Int, bool>,
+    pub frames: Vec<Frame>,
+    pub flag: Option<Ordering>,
     pub index: usize,
     pub end: bool,
+    pub overflow: OverflowMode,
+    pub input: Input,
 }
 
 impl Runtime {
-    pub fn execute(&mut self, exe: Executable) -> Result<(), RuntimeError> {
+    pub fn execute(&mut self, exe: Executable) -> Result<(), Box<RuntimeError>> {
         self.index = 0;
-        for opwrap in &exe.ops {
-            if opwrap.pre_init {
-                if let Err(err) = opwrap.op.perform(self) {
-                    return Err(self.throw_error(&exe, opwrap, err));
-                }
+        while self.index < exe.ops.len() && !self.end {
+            let opwrap = &exe.ops[self.index];
+            if let Err(err) = opwrap.op.perform(self) {
+                return Err(self.throw_error(&exe, opwrap, err));
             }
             self.index += 1;
         }
-        self.index = 0;
-        while self.index < exe.ops.len() && !self.end {
-            let opwrap = &exe.ops[self.index];
-            if !opwrap.pre_init {
-                if let Err(err) = opwrap.op.perform(self) {
-                    return Err(self.throw_error(&exe, opwrap, err));
+        Ok(())
+    }
+    pub fn get(&self, pos: &Pos) -> Result<Value, PerformError> {
+        if let Some(local) = self.frames.last().and_then(|frame| frame.locals.get(pos)) {
+            return local.ok_or(PerformError(UndefinedLocal(pos.clone())));
+        }
+        match self.memory.get(pos) {
+            Some(value) => Ok(*value),
+            None => Err(PerformError(Undefined(pos.clone()))),
+        }
+    }
+    pub fn resolve(&self, refer: &Ref) -> Result<Value, PerformError> {
+        match refer {
+            Ref::Pos(pos) => self.get(pos),
+            Ref::Value(value) => Ok(*value),
+            Ref::Index(pos, index) => {
+                let index = self.resolve_index(pos, index)?;
+                let array =
+                    unwrap_or_throw!(self.arrays.get(pos), PerformError(Undefined(pos.clone())));
+                let cell = unwrap_or_throw!(
+                    usize::try_from(index).ok().and_then(|i| array.get(i)),
+                    PerformError(OutOfBounds(pos.clone(), index))
+                );
+                cell.ok_or(PerformError(UndefinedElement(pos.clone(), index)))
+            }
+            Ref::Deref(pointer) => {
+                let address = self.resolve_address(pointer)?;
+                let index = self.heap_index(address)?;
+                let value = self.heap[index].as_ref().and_then(|cell| cell.value);
+                value.ok_or(PerformError(UndefinedAddress(address)))
+            }
+        }
+    }
+    pub fn resolve_int(&self, refer: &Ref, arg: usize) -> Result<Int, PerformError> {
+        match self.resolve(refer)? {
+            Value::Int(value) => Ok(value),
+            value => Err(PerformError(IncompatibleOperand(arg, value))),
+        }
+    }
+    fn resolve_index(&self, pos: &Pos, index: &Ref) -> Result<Int, PerformError> {
+        match self.resolve(index)? {
+            Value::Int(index) => Ok(index),
+            index => Err(PerformError(FloatIndex(pos.clone(), index))),
+        }
+    }
+    pub fn resolve_address(&self, pointer: &Ref) -> Result<Int, PerformError> {
+        match self.resolve(pointer)? {
+            Value::Int(address) => Ok(address),
+            address => Err(PerformError(InvalidAddress(address))),
+        }
+    }
+    pub fn allocate(&mut self, size: usize) -> Result<Int, PerformError> {
+        let cells = size.checked_add(1);
+        if cells.is_none_or(|cells| self.heap.try_reserve_exact(cells).is_err()) {
+            return Err(PerformError(InvalidAllocation(size as Int)));
+        }
+        let address = self.heap.len() as Int + 1;
+        self.heap.extend((0..size).map(|_| {
+            Some(HeapCell {
+                block: address,
+                value: None,
+            })
+        }));
+        self.heap.push(None);
+        self.blocks.insert(address, false);
+        Ok(address)
+    }
+    pub fn free(&mut self, address: Int) -> PerformResult {
+        match self.blocks.get_mut(&address) {
+            Some(true) => Err(PerformError(DoubleFree(address))),
+            Some(freed) => {
+                *freed = true;
+                Ok(())
+            }
+            None if (1..=self.heap.len() as Int).contains(&address) => {
+                Err(PerformError(InvalidFree(address)))
+            }
+            None => Err(PerformError(InvalidAddress(Value::Int(address)))),
+        }
+    }
+    fn heap_index(&self, address: Int) -> Result<usize, PerformError> {
+        let index = address
+            .checked_sub(1)
+            .and_then(|index| usize::try_from(index).ok());
+        let cell = index.and_then(|index| Some((index, self.heap.get(index)?.as_ref()?)));
+        let (index, cell) =
+            unwrap_or_throw!(cell, PerformError(InvalidAddress(Value::Int(address))));
+        if self.blocks[&cell.block] {
+            return Err(PerformError(UseAfterFree(address)));
+        }
+        Ok(index)
+    }
+    pub fn set(&mut self, refer: &Ref, value: Value) -> PerformResult {
+        match refer {
+            Ref::Pos(pos) => {
+                let frame = self.frames.last_mut();
+                match frame.and_then(|frame| frame.locals.get_mut(pos)) {
+                    Some(local) => *local = Some(value),
+                    None => {
+                        self.memory.insert(pos.clone(), value);
+                    }
                 }
             }
-            self.index += 1;
+            Ref::Index(pos, index) => {
+                let index = self.resolve_index(pos, index)?;
+                let array = unwrap_or_throw!(
+                    self.arrays.get_mut(pos),
+                    PerformError(Undefined(pos.clone()))
+                );
+                let cell = unwrap_or_throw!(
+                    usize::try_from(index).ok().and_then(|i| array.get_mut(i)),
+                    PerformError(OutOfBounds(pos.clone(), index))
+                );
+                *cell = Some(value);
+            }
+            Ref::Deref(pointer) => {
+                let address = self.resolve_address(pointer)?;
+                let index = self.heap_index(address)?;
+                if let Some(cell) = &mut self.heap[index] {
+                    cell.value = Some(value);
+                }
+            }
+            Ref::Value(_) => unreachable!("values are rejected as targets by the compiler"),
         }
         Ok(())
     }
-    fn throw_error(&self, exe: &Executable, opwrap: &OpWrap, err: PerformError) -> RuntimeError {
+    pub fn arithmetic(
+        &self,
+        checked: Option<Int>,
+        wrapping: Int,
+        saturating: Int,
+    ) -> Result<Int, PerformError> {
+        match (checked, self.overflow) {
+            (Some(value), _) => Ok(value),
+            (None, OverflowMode::Trap) if saturating == Int::MAX => Err(PerformError(Overflow)),
+            (None, OverflowMode::Trap) => Err(PerformError(Underflow)),
+            (None, OverflowMode::Wrap) => Ok(wrapping),
+            (None, OverflowMode::Saturate) => Ok(saturating),
+        }
+    }
+    pub fn compare(&self) -> Result<Ordering, PerformError> {
+        self.flag.ok_or(PerformError(NoCompare))
+    }
+    pub fn jump(&mut self, index: usize) {
+        self.index = index;
+    }
+    fn throw_error(
+        &self,
+        exe: &Executable,
+        opwrap: &OpWrap,
+        err: PerformError,
+    ) -> Box<RuntimeError> {
         let arg = match err.0 {
-            NoPin(_) => 1,
-            DuplicatePin(_) => 1,
+            DivisionByZero => 2,
+            InvalidShift(_) => 2,
+            InvalidAllocation(_) => 2,
+            IncompatibleOperand(arg, _) => arg,
             _ => 0,
         };
         let token = &opwrap.tokens[arg];
-        let (y, _) = token.clone().pos;
-        let lines = exe.raw.lines().collect::<Vec<_>>();
-        let length = match &token.kind {
-            TokenKind::Symbol(symbol) => symbol.len(),
-            _ => 1,
-        };
-        RuntimeError {
+        let source = &exe.sources[opwrap.source];
+        Box::new(RuntimeError {
             kind: err.0,
-            line: lines[y].to_string(),
-            loc: token.clone().pos,
-            len: length,
-        }
+            file: source.file(),
+            line: source.line(token.span),
+            span: token.span,
+        })
     }
 }